@@ -1,25 +1,57 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use super::chat_controller::{ConnectConfig, Controller, Shared};
+pub use super::color::{Color, InvalidColor, NamedColor};
+pub use super::config::{
+    AntiDupeMode, AuthServer, CapabilitySet, Config, ConfigStore, FileStore, SendBehavior,
+};
+use super::cooldown::Cooldowns;
+pub use super::emote::{emote_url, Emote, EmoteFormat, EmoteTheme};
+pub use super::error::SendError;
+pub use super::event::*;
+pub use super::message::{
+    ChatMessage, ChatMessageBuilder, ContentFlag, FlagCategory, HypeChat, ReplyInfo, ReplyParent,
+    SharedChatSource,
+};
+pub use super::outgoing::{MessageSender, Priority, Target, WHISPER_SPACING};
+use super::outgoing::{Outgoing, OutgoingQueue};
+use super::parse;
+pub use super::policy::{NoticeAction, NoticePolicy};
+use super::subscription;
+pub use super::subscription::Subscription;
+#[cfg(any(test, feature = "test-util"))]
+pub use super::transport::memory;
+use super::transport::TappedTransport;
+pub use super::transport::{BoxFuture, Connector, Transport, TransportError, WebSocketConnector};
 
-use fast_websocket_client as ws;
+///
+/// What a chat was built and configured with, for bug reports, see `Chat::build_info`.
+///
+#[derive(Debug, Clone)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// The enabled cargo features of this crate
+    pub features: Vec<&'static str>,
+    pub capabilities: CapabilitySet,
+}
 
-use super::chat_controller::{ConnectConfig, Controller};
-pub use super::config::Config;
+/// How often `Chat::run_with` checks whether the supervisor stopped while no events come in
+const RUN_STOPPED_POLL: Duration = Duration::from_millis(100);
 
 #[derive(Debug)]
 pub struct Chat {
     controller: Controller,
-    output: Receiver<ChatMessage>,
+    output: Receiver<ChatEvent>,
+    cooldowns: Mutex<Cooldowns>,
     pub config: Config,
 }
 
-#[derive(Debug)]
-pub struct ChatMessage {
-    pub author: String,
-    pub color: Option<String>,
-    pub message: String,
-}
-
 impl Default for Chat {
     fn default() -> Self {
         let mut controller = Controller::new();
@@ -29,6 +61,7 @@ impl Default for Chat {
         Self {
             controller,
             output,
+            cooldowns: Mutex::new(Cooldowns::default()),
             config,
         }
     }
@@ -39,165 +72,1101 @@ impl Chat {
         Self::default()
     }
 
+    ///
+    /// The version of this crate, eg `0.1.0`.
+    ///
+    pub fn version() -> &'static str {
+        env!("CARGO_PKG_VERSION")
+    }
+
+    pub fn build_info(&self) -> BuildInfo {
+        let features = [
+            ("client", cfg!(feature = "client")),
+            ("signal", cfg!(feature = "signal")),
+            ("test-util", cfg!(feature = "test-util")),
+        ];
+        BuildInfo {
+            version: Self::version(),
+            features: features
+                .into_iter()
+                .filter_map(|(feature, enabled)| enabled.then_some(feature))
+                .collect(),
+            capabilities: self.config.capabilities,
+        }
+    }
+
+    ///
+    /// A chat that connects through `connector` instead of a websocket,
+    /// eg the in-memory transport of the `test-util` feature.
+    ///
+    pub fn with_connector(connector: impl Connector + 'static) -> Self {
+        let mut chat = Self::default();
+        chat.controller.set_connector(Arc::new(connector));
+        chat
+    }
+
+    ///
+    /// A chat that spawns its tasks on `runtime`, rather than on the runtime it's used from.
+    ///
+    pub fn with_runtime_handle(runtime: tokio::runtime::Handle) -> Self {
+        let mut chat = Self::default();
+        chat.controller.set_runtime(runtime);
+        chat
+    }
+
     pub async fn init(&mut self) -> &mut Self {
-        if let Ok(config) = Config::load().await {
-            println!("Loaded config (~/.ttvy_core/state.json)");
-            self.config = config;
+        match Config::load().await {
+            Ok(config) => {
+                println!("Loaded config (~/.ttvy_core/state.json)");
+                self.config = config;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                eprintln!("Bad config (~/.ttvy_core/state.json): {}", e);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Config::warn_unusable_save_dir().await;
+            }
+            Err(e) => {
+                eprintln!("Failed to read config (~/.ttvy_core/state.json): {}", e);
+                Config::warn_unusable_save_dir().await;
+            }
         }
         self
     }
 
-    pub async fn send(&self, chat_message: String) {
-        self.controller.send(chat_message).await;
+    ///
+    /// Like `init`, but loads the config from `store` instead of the default file.
+    /// A store without a config yet leaves the defaults in place.
+    ///
+    pub async fn init_with(&mut self, store: &impl ConfigStore) -> &mut Self {
+        match Config::load_from(store).await {
+            Ok(config) => self.config = config,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                eprintln!("Bad config: {}", e);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Failed to read config: {}", e),
+        }
+        self
     }
 
+    ///
+    /// Without a joined channel, `config.send_behavior` decides whether the message is dropped,
+    /// fails with `SendError::NotConnected` or is held for the next `join`.
+    ///
+    pub async fn send(&self, chat_message: String) -> Result<(), SendError> {
+        self.send_priority(chat_message, Priority::Normal).await
+    }
+
+    ///
+    /// Changes the chat color of the authenticated user with `/color`,
+    /// a `Color` can only hold a name or hex color Twitch accepts.
+    ///
+    pub async fn set_color(&self, color: Color) -> Result<(), SendError> {
+        self.send(format!("/color {}", color)).await
+    }
+
+    ///
+    /// Like `send`, but while the send spacing holds messages back,
+    /// higher priority messages are sent before the ones queued with a lower priority.
+    ///
+    pub async fn send_priority(
+        &self,
+        chat_message: String,
+        priority: Priority,
+    ) -> Result<(), SendError> {
+        self.controller
+            .send(
+                None,
+                chat_message,
+                Duration::ZERO,
+                priority,
+                self.config.send_behavior,
+            )
+            .await
+    }
+
+    ///
+    /// Sends to another channel or whispers a user, through the same queue as `send`.
+    /// A whisper holds back the next message by at least `WHISPER_SPACING`, to stay within
+    /// Twitch's stricter whisper limits.
+    ///
+    pub async fn send_to_target(&self, target: Target, text: String) -> Result<(), SendError> {
+        self.controller
+            .send(
+                Some(target),
+                text,
+                Duration::ZERO,
+                Priority::Normal,
+                self.config.send_behavior,
+            )
+            .await
+    }
+
+    ///
+    /// A cloneable handle to queue messages from many tasks at once, without borrowing the chat.
+    /// Its messages are still spaced and rate limited like those of `send`.
+    /// Fails with `SendError::NotConnected` if no channel has been joined.
+    ///
+    pub fn sender(&self) -> Result<MessageSender, SendError> {
+        self.controller.sender().ok_or(SendError::NotConnected)
+    }
+
+    ///
+    /// Queues each line in order, to be sent at least `delay` apart, or `send_spacing` if that
+    /// is longer. The delay is kept by the connection, so it holds behind a backlog too.
+    /// Stops at the first line that fails to queue.
+    ///
+    pub async fn send_batch(&self, lines: Vec<String>, delay: Duration) -> Result<(), SendError> {
+        let last = lines.len().saturating_sub(1);
+        for (i, line) in lines.into_iter().enumerate() {
+            let spacing = if i < last { delay } else { Duration::ZERO };
+            self.controller
+                .send(
+                    None,
+                    line,
+                    spacing,
+                    Priority::Normal,
+                    self.config.send_behavior,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Sends the message unless `key` was used within `duration`.
+    /// Returns whether the message was sent (`true`) or suppressed by the cooldown (`false`).
+    ///
+    pub async fn send_with_cooldown(
+        &self,
+        key: &str,
+        chat_message: String,
+        duration: Duration,
+    ) -> Result<bool, SendError> {
+        if !self.cooldowns.lock().unwrap().try_use(key, duration) {
+            return Ok(false);
+        }
+
+        match self.send(chat_message).await {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                self.cooldowns.lock().unwrap().reset(key);
+                Err(e)
+            }
+        }
+    }
+
+    ///
+    /// Returns the next chat message, skipping any other events.
+    ///
+    /// Cancel safe, like `receive_event`: a message is only taken off the queue when it is returned.
+    ///
     pub async fn receive(&mut self) -> ChatMessage {
         loop {
-            match self.output.recv().await {
-                Some(msg) => return msg,
-                None => {
-                    eprintln!("Encountered empty message");
+            if let ChatEvent::Message(msg) = self.receive_event().await {
+                return msg;
+            }
+        }
+    }
+
+    ///
+    /// Returns the next event.
+    ///
+    /// Cancel safe, eg when used as a `tokio::select!` branch next to a timeout.
+    /// It only awaits `mpsc::Receiver::recv`, which is cancel safe, and the resume notification,
+    /// so a cancelled call never removes an event without returning it.
+    ///
+    pub async fn receive_event(&mut self) -> ChatEvent {
+        loop {
+            if self.controller.is_paused() {
+                self.controller.resumed().await;
+                continue;
+            }
+            if let Some(event) = self.try_receive_event() {
+                return event;
+            }
+
+            tokio::select! {
+                event = self.output.recv() => match event {
+                    Some(event) => return event,
+                    None => {
+                        eprintln!("Encountered empty message");
+                    }
+                },
+                _ = self.controller.resumed() => {}
+            }
+        }
+    }
+
+    fn try_receive_event(&mut self) -> Option<ChatEvent> {
+        if self.controller.is_paused() {
+            return None;
+        }
+        self.output
+            .try_recv()
+            .ok()
+            .or_else(|| self.controller.take_buffered())
+    }
+
+    ///
+    /// Passes every event to `handler` until it returns `false`, then closes the chat.
+    /// Reconnects are left to the supervisor as usual. Once it stopped for good,
+    /// eg on a login conflict, the events before that are handled and this returns.
+    ///
+    /// The chat is borrowed for the whole run, so a handler replies through a `sender`
+    /// taken before.
+    ///
+    pub async fn run_with<F, Fut>(&mut self, mut handler: F)
+    where
+        F: FnMut(ChatEvent) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        loop {
+            let event = tokio::select! {
+                event = self.receive_event() => event,
+                _ = tokio::time::sleep(RUN_STOPPED_POLL) => {
+                    if self.is_running() {
+                        continue;
+                    }
+                    // the supervisor queued its last events, eg `Disconnected`, before it ended
+                    match self.try_receive_event() {
+                        Some(event) => event,
+                        None => return,
+                    }
                 }
+            };
+
+            if !handler(event).await {
+                self.close().await;
+                return;
             }
         }
     }
 
+    ///
+    /// Whether the chat is still connected or reconnecting. `false` once it stopped,
+    /// eg on a NOTICE policy or a crashed task, which `reconnect` starts again.
+    ///
+    pub fn is_running(&self) -> bool {
+        self.controller.is_running()
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.controller.connection_state().borrow()
+    }
+
+    ///
+    /// Whether the server finished registration with its `001` welcome.
+    ///
+    pub fn is_connected(&self) -> bool {
+        self.connection_state() == ConnectionState::Connected
+    }
+
+    ///
+    /// Time since the last frame was received, including PINGs, or since the chat was created.
+    /// A quiet channel still gets PINGs about every 5 minutes, a dead connection doesn't.
+    ///
+    pub fn idle_duration(&self) -> Duration {
+        self.controller.idle_duration()
+    }
+
+    ///
+    /// Waits until the server sent its `001` welcome, eg before sending right after `join`.
+    ///
+    pub async fn await_connected(&self) {
+        let mut state = self.controller.connection_state();
+        let _ = state
+            .wait_for(|state| *state == ConnectionState::Connected)
+            .await;
+    }
+
+    ///
+    /// Always holds the newest message received in `channel`, for displays that only show that.
+    ///
+    pub fn latest(&self, channel: &str) -> tokio::sync::watch::Receiver<Option<ChatMessage>> {
+        self.controller.latest(&parse::normalize_channel(channel))
+    }
+
+    ///
+    /// Every event, like `receive_event`, but a separate copy for each subscription.
+    ///
+    pub fn subscribe(&self) -> Subscription<ChatEvent> {
+        Subscription::new(self.controller.subscribe(), subscription::all)
+    }
+
+    ///
+    /// Every received IRC line before it's parsed, for debugging or for what the crate doesn't parse.
+    /// Only fed while `config.raw_events` was set on `join`.
+    ///
+    pub fn raw_events(&self) -> Subscription<String, String> {
+        Subscription::new(self.controller.subscribe_raw(), subscription::all)
+    }
+
+    ///
+    /// Every line sent to the server, after anti-dupe and formatting, to audit what Twitch got.
+    /// The token of the PASS line is masked. Only fed while `config.raw_outgoing` was set
+    /// on `join`, dry run messages aren't sent and don't show up here.
+    ///
+    pub fn sent_lines(&self) -> Subscription<String, String> {
+        Subscription::new(self.controller.subscribe_sent(), subscription::all)
+    }
+
+    pub fn message_events(&self) -> Subscription<ChatMessage> {
+        Subscription::new(self.controller.subscribe(), subscription::messages)
+    }
+
+    ///
+    /// Bans and timeouts, leaving out CLEARCHATs that clear the whole chat.
+    ///
+    pub fn ban_events(&self) -> Subscription<ClearChat> {
+        Subscription::new(self.controller.subscribe(), subscription::bans)
+    }
+
+    ///
+    /// Subs and resubs, gifts have their own `ChatEvent`s.
+    ///
+    pub fn sub_events(&self) -> Subscription<SubEvent> {
+        Subscription::new(self.controller.subscribe(), subscription::subs)
+    }
+
+    pub fn raid_events(&self) -> Subscription<RaidEvent> {
+        Subscription::new(self.controller.subscribe(), subscription::raids)
+    }
+
+    ///
+    /// Holds back incoming events until `resume` is called, also the ones already waiting.
+    /// Events received while paused are kept in a bounded buffer, dropping the oldest.
+    ///
+    pub fn pause(&self) {
+        self.controller.pause();
+    }
+
+    pub fn resume(&self) {
+        self.controller.resume();
+    }
+
+    ///
+    /// Takes the lines that would have been sent while `config.dry_run` is set,
+    /// the last 1024 of them.
+    ///
+    pub fn take_dry_run_messages(&self) -> Vec<String> {
+        self.controller.take_dry_run_messages()
+    }
+
+    ///
+    /// The authenticated user from the latest USERSTATE/GLOBALUSERSTATE, if any was received.
+    ///
+    pub fn self_user(&self) -> Option<SelfUser> {
+        self.controller.self_user()
+    }
+
+    ///
+    /// Membership, room settings and message rate of every channel seen on this chat.
+    ///
+    pub fn channels_snapshot(&self) -> Vec<ChannelStatus> {
+        self.controller.channels_snapshot()
+    }
+
+    ///
+    /// Connection state, idle time and message rates, like the `ChatEvent::Status` heartbeat.
+    ///
+    pub fn status(&self) -> Status {
+        self.controller.status()
+    }
+
+    ///
+    /// How long until a message to `channel` gets past its slow mode, based on our last send there.
+    /// `None` when a message can be sent now. Moderators and the broadcaster are exempt,
+    /// which this doesn't account for.
+    ///
+    pub fn slow_mode_remaining(&self, channel: &str) -> Option<Duration> {
+        self.controller
+            .slow_mode_remaining(&parse::normalize_channel(channel))
+    }
+
+    ///
+    /// The last `limit` bans, timeouts, chat clears and deleted messages seen in `channel`,
+    /// oldest first. Only kept in memory, for the most recent 256 per channel.
+    ///
+    pub fn recent_mod_actions(&self, channel: &str, limit: usize) -> Vec<ModAction> {
+        self.controller
+            .recent_mod_actions(&parse::normalize_channel(channel), limit)
+    }
+
+    ///
+    /// The last `limit` messages and moderation actions of `channel` in the order they came in,
+    /// with deleted messages and the messages of banned users marked,
+    /// for reviewing a channel like a moderator. Empty unless `mod_view` is enabled.
+    ///
+    pub fn timeline(&self, channel: &str, limit: usize) -> Vec<TimelineEntry> {
+        self.controller
+            .timeline(&parse::normalize_channel(channel), limit)
+    }
+
+    ///
+    /// The last `limit` messages of `channel`, oldest first, including the ones saved by an
+    /// earlier run. Empty unless `history_size` is set.
+    ///
+    pub fn history(&self, channel: &str, limit: usize) -> Vec<ChatMessage> {
+        self.controller
+            .history(&parse::normalize_channel(channel), limit)
+    }
+
+    ///
+    /// Saves the history to `history_path` now rather than on `leave` or `close`,
+    /// eg periodically in case the process is killed.
+    ///
+    pub async fn save_history(&self) -> Result<(), tokio::io::Error> {
+        self.controller.save_history().await
+    }
+
+    ///
+    /// The `broadcaster-lang` of `channel` from its ROOMSTATE, if Twitch sent one.
+    ///
+    pub fn broadcaster_lang(&self, channel: &str) -> Option<String> {
+        let channel = parse::normalize_channel(channel);
+        self.channels_snapshot()
+            .into_iter()
+            .find(|status| status.channel == channel)?
+            .room_state?
+            .broadcaster_lang
+    }
+
     pub fn join(&mut self, channel: &str) {
         self.config.channel.replace(channel.to_string());
         self.controller.join(self.config.clone().into());
     }
 
+    ///
+    /// Moves to `channel` with a PART and JOIN on the current connection,
+    /// keeping the session and queued messages. Joins with a new connection when not connected.
+    ///
+    pub async fn switch_channel(&mut self, channel: &str) {
+        self.config.channel.replace(channel.to_string());
+        if !self.is_connected()
+            || self
+                .controller
+                .switch_channel(channel.to_string())
+                .await
+                .is_err()
+        {
+            self.controller.join(self.config.clone().into());
+        }
+    }
+
     pub async fn leave(&mut self) {
         self.controller.leave().await;
         println!("Disconnected");
     }
 
+    ///
+    /// Leaves the channel with a PART and waits for the connection to end.
+    ///
+    pub async fn close(&mut self) {
+        self.controller.close().await;
+        println!("Disconnected");
+    }
+
+    ///
+    /// Returns the next event, or closes the chat and returns `None` once Ctrl-C is pressed.
+    ///
+    #[cfg(feature = "signal")]
+    pub async fn receive_event_until_ctrl_c(&mut self) -> Option<ChatEvent> {
+        tokio::select! {
+            event = self.receive_event() => Some(event),
+            _ = tokio::signal::ctrl_c() => {
+                self.close().await;
+                None
+            }
+        }
+    }
+
+    ///
+    /// Joins `config.channel`, falling back to `config.channels` in order
+    /// whenever a channel can't be joined, eg because it is suspended.
+    ///
+    pub fn join_any(&mut self) {
+        self.reconnect();
+    }
+
     pub fn reconnect(&mut self) {
-        if self.config.channel.is_some() {
+        if self.config.channel.is_some() || !self.config.channels.is_empty() {
             self.controller.join(self.config.clone().into());
         } else {
             println!("No recently joined channel to reconnect to");
         }
     }
 
+    ///
+    /// The websocket URL of the last or current connection, or the one the next `join` would use.
+    ///
+    pub fn endpoint(&self) -> &str {
+        self.controller
+            .endpoint()
+            .unwrap_or_else(|| self.config.endpoint())
+    }
+
+    ///
+    /// The configuration in use after loading the saved state, reading arguments and joining,
+    /// with the oauth token redacted so it can be pasted into bug reports.
+    ///
+    pub fn effective_config(&self) -> Config {
+        self.config.redacted()
+    }
+
     pub async fn fetch_auth_token(&mut self) -> &mut Self {
         Config::fetch_auth_token(&mut self.config).await;
         self
     }
+
+    pub async fn try_fetch_auth_token(&mut self) -> Result<&mut Self, tokio::io::Error> {
+        Config::try_fetch_auth_token(&mut self.config).await?;
+        Ok(self)
+    }
+
+    pub async fn fetch_auth_token_with(&mut self, server: &mut AuthServer) -> &mut Self {
+        Config::fetch_auth_token_with(&mut self.config, server).await;
+        self
+    }
 }
 
 ///
 /// `incoming_message_tx` is a sender of messages. The websocket will transmit its incoming
 /// messages over this Sender.
-/// `outgoing_message_rx` is a receiver of messages transmitted by users of this library
+/// `outgoing_message_rx` is a receiver of messages transmitted by users of this library,
+/// shared with later connections so queued messages survive a reconnect
+/// `shared` holds the state read back through the controller, eg the dry run log
+/// `connector` opens the connection, a websocket unless the chat was built `with_connector`
 ///
 pub(super) async fn connect(
     connect_config: ConnectConfig,
-    incoming_message_tx: Sender<ChatMessage>,
-    mut outgoing_message_rx: Receiver<String>,
+    incoming_message_tx: Sender<ChatEvent>,
+    outgoing_message_rx: Arc<tokio::sync::Mutex<OutgoingQueue>>,
     mut shutdown_rx: oneshot::Receiver<()>,
-) {
+    shared: Arc<Shared>,
+    connector: Arc<dyn Connector>,
+) -> DisconnectReason {
     {
+        let nick_name = login_nick(&connect_config);
         let ConnectConfig {
-            channel,
-            mut oauth,
-            mut nick,
+            ref channel,
+            dry_run,
+            ref notice_policy,
+            ref endpoint,
+            send_spacing,
+            antidupe,
+            handshake_timeout,
+            raw_events,
+            raw_outgoing,
+            trim_trailing_whitespace,
+            ref ignored_prefixes,
+            mod_view,
+            auto_rejoin,
+            ref history,
+            ..
         } = connect_config;
 
-        let channel = channel.unwrap();
-
-        let join = format!("JOIN #{}\n\r", &channel);
-        let oauth = format!(
-            "PASS oauth:{}",
-            oauth.get_or_insert_with(|| "blah".to_string())
-        );
-        let nick = format!(
-            "NICK {}\n\r",
-            nick.get_or_insert_with(|| "justinfan354678".to_string())
-        );
+        let Some(display_channel) = channel else {
+            return DisconnectReason::Shutdown;
+        };
+        let mut display_channel = display_channel.trim().trim_start_matches('#').to_string();
+        let mut channel = parse::normalize_channel(&display_channel);
 
-        let mut conn = ws::connect("ws://irc-ws.chat.twitch.tv:80").await.unwrap();
-        conn.set_auto_pong(true);
+        shared
+            .connection_state
+            .send_replace(ConnectionState::Connecting);
+        let mut conn = match connector.connect(endpoint).await {
+            Ok(conn) if raw_outgoing => Box::new(TappedTransport {
+                inner: conn,
+                tap: shared.sent_lines.clone(),
+            }),
+            Ok(conn) => conn,
+            Err(e) => return DisconnectReason::Connection(e.to_string()),
+        };
 
-        conn.send_string(&oauth).await.unwrap();
-        conn.send_string(&nick).await.unwrap();
-        conn.send_string(&join).await.unwrap();
-        conn.send_string("CAP REQ :twitch.tv/tags").await.unwrap();
+        let cap_requested = match perform_handshake(conn.as_mut(), &connect_config).await {
+            Ok(cap_requested) => cap_requested,
+            Err(e) => return DisconnectReason::Connection(e.to_string()),
+        };
 
+        let mut outgoing_message_rx = outgoing_message_rx.lock().await;
         let mut read_tags_allowed = false;
+        // without a CAP REQ there is no ACK to wait for
+        let mut acknowledged = !cap_requested;
         let mut last_sent_message = String::new();
-        println!("Joined channel #{}", &channel);
+        // outgoing messages are only taken off the queue once `send_spacing` has passed
+        let pacing = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(pacing);
+        let mut paced = true;
+        let handshake_deadline = tokio::time::sleep(handshake_timeout);
+        tokio::pin!(handshake_deadline);
+        let rejoin = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(rejoin);
+        let mut rejoin_pending = false;
+        let mut rejoin_attempts = 0;
+        let mut acknowledged_caps: Vec<String> = vec![];
+        let mut announced = false;
+        println!("Joined channel #{}", &display_channel);
         loop {
             tokio::select! {
-                res = conn.receive_frame() => {
-                    match res {
-                        Ok(f) => {
-                            let msg = if let Ok(s) = std::str::from_utf8(&f.payload) {
-                                s.to_string()
-                            } else {
-                                f.payload
-                                    .iter()
-                                    .map(|v| -> char { (*v).into() })
-                                    .collect::<String>()
-                            };
-
-                            handle_websocket_message(&incoming_message_tx, msg, &mut read_tags_allowed).await;
-                        }
+                res = conn.receive() => {
+                    let frame = match res {
+                        Ok(frame) => frame,
                         Err(e) => {
                             println!("{}", e);
-                            break;
+                            return DisconnectReason::Connection(e.to_string());
+                        }
+                    };
+                    *shared.last_received.lock().unwrap() = Instant::now();
+
+                    // a frame can hold several lines, eg the USERSTATE and ROOMSTATE of a join
+                    for msg in frame.split("\r\n").filter(|line| !line.trim().is_empty()) {
+                        let msg = msg.to_string();
+                        if raw_events {
+                            // no subscribers is fine, lines are only kept for the ones there are
+                            let _ = shared.raw_lines.send(msg.clone());
+                        }
+                        if parse::is_login_conflict(&msg) {
+                            println!("Logged in elsewhere: {}", msg.trim_end());
+                            return DisconnectReason::LoginConflict;
+                        }
+                        if parse::is_command(&msg, "RECONNECT") {
+                            println!("Server requested a reconnect");
+                            return DisconnectReason::Reconnect;
+                        }
+
+                        if parse::is_command(&msg, "CAP") && msg.contains(" ACK ") {
+                            acknowledged = true;
+                            acknowledged_caps.extend(acknowledged_capabilities(&msg));
+                        }
+                        let parted = parse::is_command(&msg, "PART")
+                            && parse::prefix_nick(&msg) == Some(nick_name.as_str())
+                            && parse::channel(&msg) == Some(channel.as_str());
+                        match auto_rejoin {
+                            Some(limit) if parted && rejoin_attempts < limit => {
+                                let delay = rejoin_delay(rejoin_attempts);
+                                println!("Parted from #{}, rejoining in {:?}", &channel, delay);
+                                rejoin.as_mut().reset(Instant::now() + delay);
+                                rejoin_pending = true;
+                                rejoin_attempts += 1;
+                            }
+                            Some(_) if parted => {
+                                println!("Parted from #{}, not rejoining again", &channel);
+                            }
+                            _ => {}
+                        }
+                        let options = ReceiveOptions {
+                            notice_policy,
+                            nick: &nick_name,
+                            display_channel: &display_channel,
+                            trim_trailing_whitespace,
+                            ignored_prefixes,
+                            mod_view,
+                            history_size: history.as_ref().map(|history| history.size),
+                        };
+                        let action = handle_websocket_message(
+                            &incoming_message_tx,
+                            msg,
+                            &mut read_tags_allowed,
+                            &shared,
+                            options,
+                        )
+                        .await;
+
+                        if let Some((msg_id, action)) = action {
+                            println!("Disconnecting on NOTICE {}", &msg_id);
+                            return DisconnectReason::Notice { msg_id, action };
+                        }
+
+                        let connected =
+                            *shared.connection_state.borrow() == ConnectionState::Connected;
+                        if !announced && acknowledged && connected {
+                            announced = true;
+                            let anonymous = nick_name.starts_with("justinfan");
+                            let info = ConnectedInfo {
+                                endpoint: endpoint.clone(),
+                                capabilities: acknowledged_caps.clone(),
+                                login: (!anonymous).then(|| nick_name.clone()),
+                                channels: vec![display_channel.clone()],
+                                anonymous,
+                            };
+                            incoming_message_tx
+                                .send(ChatEvent::Connected(info))
+                                .await
+                                .expect("Controller proxy should be set up");
                         }
                     }
                 }
-                msg = outgoing_message_rx.recv() => {
-                    if let Some(mut msg) = msg {
+                _ = &mut handshake_deadline, if !acknowledged => {
+                    println!("No capability acknowledgement after {:?}", handshake_timeout);
+                    return DisconnectReason::HandshakeTimeout;
+                }
+                _ = &mut rejoin, if rejoin_pending => {
+                    rejoin_pending = false;
+                    if let Err(e) = conn.send_line(&JoinCommand::new(&channel).build()).await {
+                        return DisconnectReason::Connection(e.to_string());
+                    }
+                }
+                _ = &mut pacing, if !paced => {
+                    paced = true;
+                }
+                msg = outgoing_message_rx.next(), if paced => {
+                    let Some(msg) = msg else {
+                        // every sender is gone, the chat is leaving or joined elsewhere
+                        let _ = conn.send_line(&format!("PART #{}", &channel)).await;
+                        return DisconnectReason::Shutdown;
+                    };
+                    if let Outgoing::SwitchChannel(new_channel) = msg {
+                        let new_display_channel = new_channel.trim().trim_start_matches('#');
+                        let new_channel = parse::normalize_channel(new_display_channel);
+                        for line in [
+                            format!("PART #{}", &channel),
+                            JoinCommand::new(&new_channel).build(),
+                        ] {
+                            if let Err(e) = conn.send_line(&line).await {
+                                let switch = new_display_channel.to_string();
+                                outgoing_message_rx.requeue(Outgoing::SwitchChannel(switch));
+                                return DisconnectReason::Connection(e.to_string());
+                            }
+                        }
+                        println!("Switched to channel #{}", new_display_channel);
+                        rejoin_pending = false;
+                        display_channel = new_display_channel.to_string();
+                        channel = new_channel;
+                        shared
+                            .switched_channel
+                            .lock()
+                            .unwrap()
+                            .replace(display_channel.clone());
+                    } else if let Outgoing::Message {
+                        target: Some(target),
+                        text,
+                        spacing: hold,
+                    } = msg
+                    {
+                        // whispers aren't held to the slow mode of the channel they go through
+                        let (fmt, spacing, slowed) = match &target {
+                            Target::Channel(target) => {
+                                let target = parse::normalize_channel(target);
+                                let fmt = format!("PRIVMSG #{} :{}", target, &text);
+                                (fmt, send_spacing, Some(target))
+                            }
+                            Target::Whisper(user) => {
+                                let user = user.trim_start_matches('@').to_lowercase();
+                                (
+                                    format!("PRIVMSG #{} :/w {} {}", &channel, user, &text),
+                                    send_spacing.max(WHISPER_SPACING),
+                                    None,
+                                )
+                            }
+                        };
+                        if dry_run {
+                            println!("(dry run) {}", &fmt);
+                            shared.log_dry_run(fmt);
+                        } else {
+                            if let Some(slowed) = slowed {
+                                shared
+                                    .channels
+                                    .lock()
+                                    .unwrap()
+                                    .entry(slowed)
+                                    .or_default()
+                                    .record_send();
+                            }
+                            if let Err(e) = conn.send_line(&fmt).await {
+                                let target = Some(target);
+                                let msg = Outgoing::Message { target, text, spacing: hold };
+                                outgoing_message_rx.requeue(msg);
+                                return DisconnectReason::Connection(e.to_string());
+                            }
+                        }
+                        pacing.as_mut().reset(Instant::now() + spacing.max(hold));
+                        paced = false;
+                    } else if let Outgoing::Message { text: mut msg, spacing: hold, .. } = msg {
                         if msg.is_empty() {
                             msg = last_sent_message.clone();
                         }
+                        // as queued, the anti-dupe suffix is up to the connection that sends it
+                        let queued = msg.clone();
 
-                        if last_sent_message == msg {
-                            if msg.contains(" \u{E0000}") {
-                                msg = msg.strip_suffix(" \u{E0000}").unwrap().to_string();
+                        if let (AntiDupeMode::Char(c), true) = (antidupe, last_sent_message == msg) {
+                            let suffix = format!(" {}", c);
+                            if let Some(stripped) = msg.strip_suffix(&suffix) {
+                                msg = stripped.to_string();
                             } else {
-                                msg.push_str(" \u{E0000}");
+                                msg.push_str(&suffix);
                             }
                         }
 
                         last_sent_message = msg.clone();
 
                         let fmt = format!("PRIVMSG #{} :{}", &channel, &msg);
-                        let _ = conn.send_string(&fmt).await;
+                        if dry_run {
+                            println!("(dry run) {}", &fmt);
+                            shared.log_dry_run(fmt);
+                        } else {
+                            shared
+                                .channels
+                                .lock()
+                                .unwrap()
+                                .entry(channel.clone())
+                                .or_default()
+                                .record_send();
+                            if let Err(e) = conn.send_line(&fmt).await {
+                                let msg = Outgoing::Message {
+                                    target: None,
+                                    text: queued,
+                                    spacing: hold,
+                                };
+                                outgoing_message_rx.requeue(msg);
+                                return DisconnectReason::Connection(e.to_string());
+                            }
+                        }
+                        pacing.as_mut().reset(Instant::now() + send_spacing.max(hold));
+                        paced = false;
                     }
                 }
                 _ = &mut shutdown_rx => {
-                    break;
+                    let _ = conn.send_line(&format!("PART #{}", &channel)).await;
+                    return DisconnectReason::Shutdown;
                 }
             };
         }
     }
 }
 
+/// Wait before the first `auto_rejoin`, doubled for each attempt after it
+const REJOIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_REJOIN_BACKOFF: Duration = Duration::from_secs(60);
+
+fn rejoin_delay(attempt: u32) -> Duration {
+    REJOIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_REJOIN_BACKOFF)
+}
+
+///
+/// Builds a JOIN line, so options of a join have one place to go instead of each `format!`.
+///
+#[derive(Debug, Clone)]
+struct JoinCommand {
+    channel: String,
+    line_ending: &'static str,
+}
+
+impl JoinCommand {
+    fn new(channel: &str) -> Self {
+        Self {
+            channel: parse::normalize_channel(channel),
+            line_ending: "",
+        }
+    }
+
+    ///
+    /// Appended to the line, the login lines of the handshake end in `\n\r`.
+    ///
+    fn line_ending(mut self, line_ending: &'static str) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    fn build(&self) -> String {
+        format!("JOIN #{}{}", self.channel, self.line_ending)
+    }
+}
+
+///
+/// The capabilities of a `CAP * ACK :a b` line.
+///
+fn acknowledged_capabilities(line: &str) -> Vec<String> {
+    line.lines()
+        .next()
+        .and_then(|line| line.split_once(" ACK :"))
+        .map(|(_, caps)| caps.split_whitespace().map(|c| c.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Logged in as when no nick is configured, Twitch's anonymous read-only login
+const ANONYMOUS_NICK: &str = "justinfan354678";
+
+fn login_nick(config: &ConnectConfig) -> String {
+    config
+        .nick
+        .as_deref()
+        .unwrap_or(ANONYMOUS_NICK)
+        .to_lowercase()
+}
+
+///
+/// Sends PASS, NICK, JOIN and the CAP REQ of `config`, the same lines on every (re)connect.
+/// Returns whether a CAP REQ was sent, which the server is expected to acknowledge.
+///
+pub(super) async fn perform_handshake(
+    conn: &mut dyn Transport,
+    config: &ConnectConfig,
+) -> Result<bool, TransportError> {
+    let oauth = format!("PASS oauth:{}", config.oauth.as_deref().unwrap_or("blah"));
+    let nick = format!("NICK {}\n\r", login_nick(config));
+    let join = config
+        .channel
+        .as_ref()
+        .map(|channel| JoinCommand::new(channel).line_ending("\n\r").build());
+    let cap_req = config.capabilities.request_line();
+
+    for line in [Some(oauth), Some(nick), join, cap_req.clone()]
+        .iter()
+        .flatten()
+    {
+        conn.send_line(line).await?;
+    }
+    Ok(cap_req.is_some())
+}
+
+///
+/// Returns the NOTICE `msg-id` and action if the policy asks to end the connection.
+///
 async fn handle_websocket_message(
-    incoming_message_tx: &Sender<ChatMessage>,
+    incoming_message_tx: &Sender<ChatEvent>,
     msg: String,
     read_tags_allowed: &mut bool,
-) {
+    shared: &Shared,
+    options: ReceiveOptions<'_>,
+) -> Option<(String, NoticeAction)> {
+    let ReceiveOptions {
+        notice_policy,
+        nick,
+        ..
+    } = options;
+
     match msg {
-        m if m.contains("ACK :twitch.tv/tags") => {
-            *read_tags_allowed = true;
+        m if parse::is_command(&m, "CAP") && m.contains(" ACK ") => {
+            if m.contains("twitch.tv/tags") {
+                *read_tags_allowed = true;
+            }
+        }
+        m if parse::is_command(&m, "001") => {
+            shared
+                .connection_state
+                .send_replace(ConnectionState::Connected);
         }
-        m if *read_tags_allowed && m.contains("PRIVMSG") => {
-            if let Some(user_message) = parse::format_user_message_with_tags(&m) {
+        // the rest of the welcome and the MOTD
+        m if ["002", "003", "004", "375", "372", "376"]
+            .iter()
+            .any(|numeric| parse::is_command(&m, numeric)) => {}
+        m if *read_tags_allowed && parse::is_command(&m, "USERNOTICE") => {
+            if let Some(event) = parse::parse_user_notice(&m) {
                 incoming_message_tx
-                    .send(user_message)
+                    .send(event)
                     .await
                     .expect("Controller proxy should be set up")
             }
         }
-        m if m.contains("PRIVMSG") => {
-            if let Some(user_message) = parse::format_user_message(&m) {
+        m if parse::is_command(&m, "NOTICE") => {
+            if let Some(event) = parse::parse_notice(&m) {
+                let action = match &event {
+                    ChatEvent::Notice(Notice {
+                        msg_id: Some(msg_id),
+                        ..
+                    }) => notice_policy
+                        .action(msg_id)
+                        .map(|action| (msg_id.clone(), action)),
+                    _ => None,
+                };
+
+                incoming_message_tx
+                    .send(event)
+                    .await
+                    .expect("Controller proxy should be set up");
+                return action;
+            }
+        }
+        m if parse::is_command(&m, "USERSTATE") || parse::is_command(&m, "GLOBALUSERSTATE") => {
+            if let Some(user) = parse::parse_user_state(&m) {
+                let mut events = vec![];
+                {
+                    let mut self_user = shared.self_user.lock().unwrap();
+                    let previous = self_user.replace(user.clone());
+                    if let (Some(previous), Some(self_user)) = (previous, self_user.as_mut()) {
+                        if self_user.user_id.is_none() {
+                            self_user.user_id = previous.user_id;
+                        }
+                        if previous.color != user.color {
+                            events.push(ChatEvent::SelfColorChanged(user.color));
+                        }
+
+                        let added: Vec<String> = user
+                            .emote_sets
+                            .into_iter()
+                            .filter(|set| !previous.emote_sets.contains(set))
+                            .collect();
+                        if !added.is_empty() {
+                            events.push(ChatEvent::EmoteSetsAdded(added));
+                        }
+                    }
+                }
+
+                for event in events {
+                    incoming_message_tx
+                        .send(event)
+                        .await
+                        .expect("Controller proxy should be set up");
+                }
+            }
+        }
+        m if parse::is_command(&m, "JOIN") || parse::is_command(&m, "PART") => {
+            if let (Some(user), Some(channel)) = (parse::prefix_nick(&m), parse::channel(&m)) {
+                if user == nick {
+                    let mut channels = shared.channels.lock().unwrap();
+                    channels.entry(channel.to_string()).or_default().joined =
+                        parse::is_command(&m, "JOIN");
+                }
+            }
+            println!("{}", &m);
+        }
+        m if parse::is_command(&m, "CLEARCHAT") => {
+            if let Some(clear_chat) = parse::parse_clear_chat(&m) {
+                record_mod_action(shared, ModAction::ClearChat(clear_chat.clone()), options);
+                incoming_message_tx
+                    .send(ChatEvent::ClearChat(clear_chat))
+                    .await
+                    .expect("Controller proxy should be set up");
+            }
+        }
+        m if parse::is_command(&m, "CLEARMSG") => {
+            if let Some(clear_msg) = parse::parse_clear_msg(&m) {
+                record_mod_action(shared, ModAction::ClearMsg(clear_msg.clone()), options);
+                incoming_message_tx
+                    .send(ChatEvent::ClearMsg(clear_msg))
+                    .await
+                    .expect("Controller proxy should be set up");
+            }
+        }
+        m if parse::is_command(&m, "ROOMSTATE") => {
+            if let Some(update) = parse::parse_room_state(&m) {
+                let room_state = {
+                    let mut channels = shared.channels.lock().unwrap();
+                    let state = channels.entry(update.channel.clone()).or_default();
+                    let room_state = state.room_state.get_or_insert_with(RoomState::default);
+                    room_state.merge(update);
+                    room_state.clone()
+                };
+
+                incoming_message_tx
+                    .send(ChatEvent::RoomState(room_state))
+                    .await
+                    .expect("Controller proxy should be set up");
+            }
+        }
+        m if parse::is_command(&m, "PRIVMSG") => {
+            record_message(shared, &m);
+            if let Some(mut user_message) = parse::parse_privmsg(&m) {
+                if !options.prepare(&mut user_message) {
+                    return None;
+                }
+                record_received_message(shared, &user_message, options);
                 incoming_message_tx
-                    .send(user_message)
+                    .send(ChatEvent::Message(user_message))
                     .await
                     .expect("Controller proxy should be set up");
             }
@@ -206,70 +1175,633 @@ async fn handle_websocket_message(
             println!("{}", &m);
         }
     }
+
+    None
 }
 
-mod parse {
-    use std::collections::HashMap;
+///
+/// What the connection knows about how incoming lines are handled.
+///
+#[derive(Clone, Copy)]
+struct ReceiveOptions<'a> {
+    notice_policy: &'a NoticePolicy,
+    nick: &'a str,
+    display_channel: &'a str,
+    trim_trailing_whitespace: bool,
+    ignored_prefixes: &'a [String],
+    mod_view: bool,
+    history_size: Option<usize>,
+}
 
-    use super::ChatMessage;
+impl ReceiveOptions<'_> {
+    ///
+    /// Fills in and trims a received message, `false` if it is to be dropped.
+    ///
+    fn prepare(&self, message: &mut ChatMessage) -> bool {
+        if message.has_prefix(self.ignored_prefixes) {
+            return false;
+        }
 
-    pub fn format_user_message(str: &str) -> Option<ChatMessage> {
-        let str = str.split_once("\r\n").unwrap().0;
+        if message.channel.as_deref() == Some(&parse::normalize_channel(self.display_channel)) {
+            message.display_channel = Some(self.display_channel.to_string());
+        }
 
-        let author = if let Some((author, _)) = str.split_once('!') {
-            Some(author.get(1..).unwrap().to_string())
-        } else {
-            None
+        if self.trim_trailing_whitespace {
+            // the anti-dupe character of the sender would keep the whitespace before it
+            let trimmed = message
+                .message
+                .trim_end_matches(|c: char| c == '\u{E0000}' || c.is_whitespace());
+            message.message.truncate(trimmed.len());
+        }
+        true
+    }
+}
+
+fn record_mod_action(shared: &Shared, action: ModAction, options: ReceiveOptions<'_>) {
+    let channel = match &action {
+        ModAction::ClearChat(clear_chat) => clear_chat.channel.clone(),
+        ModAction::ClearMsg(clear_msg) => clear_msg.channel.clone(),
+    };
+    let mut channels = shared.channels.lock().unwrap();
+    let state = channels.entry(channel).or_default();
+    if options.mod_view {
+        state.record_timeline_action(&action);
+    }
+    state.record_mod_action(action);
+}
+
+fn record_received_message(shared: &Shared, message: &ChatMessage, options: ReceiveOptions<'_>) {
+    if !options.mod_view && options.history_size.is_none() {
+        return;
+    }
+    let Some(channel) = message.channel.as_ref() else {
+        return;
+    };
+    let mut channels = shared.channels.lock().unwrap();
+    let state = channels.entry(channel.clone()).or_default();
+    if options.mod_view {
+        state.record_timeline_message(message.clone());
+    }
+    if let Some(size) = options.history_size {
+        state.record_history(message.clone(), size);
+    }
+}
+
+fn record_message(shared: &Shared, line: &str) {
+    if let Some(channel) = parse::channel(line) {
+        let mut channels = shared.channels.lock().unwrap();
+        channels
+            .entry(channel.to_string())
+            .or_default()
+            .record_message();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::memory::{MemoryConnection, MemoryServer};
+    use super::*;
+
+    /// Long enough to never pass in a working test, so a hang fails instead of blocking
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    const PRIVMSG: &str = "@badge-info=;badges=;color=#1E90FF;display-name=Viewer;emotes=;\
+        first-msg=0;flags=;id=b34ccfc7-4977-403a-8a94-33c6bac34fb8;mod=0;room-id=1337;\
+        subscriber=0;tmi-sent-ts=1507246572675;turbo=0;user-id=1338;user-type= \
+        :viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #channel :hello chat";
+
+    const USERNOTICE: &str = "@badge-info=subscriber/1;badges=subscriber/0;color=;\
+        display-name=Subber;emotes=;flags=;id=db25007f-7a18-43eb-9379-80131e44d633;login=subber;\
+        mod=0;msg-id=sub;msg-param-cumulative-months=1;msg-param-months=0;\
+        msg-param-should-share-streak=0;msg-param-sub-plan-name=Channel\\sSubscription\\s(channel);\
+        msg-param-sub-plan=1000;room-id=1337;subscriber=1;\
+        system-msg=Subber\\ssubscribed\\sat\\sTier\\s1.;tmi-sent-ts=1507246572675;user-id=1339;\
+        user-type= :tmi.twitch.tv USERNOTICE #channel :first sub";
+
+    fn memory_chat() -> (Chat, MemoryServer) {
+        let (connector, server) = memory::pair();
+        let mut chat = Chat::with_connector(connector);
+        chat.config.reconnect_jitter_ms = Some(0);
+        // the NOTICE, RECONNECT and USERNOTICE the tests send only come with `commands`
+        chat.config.capabilities.commands = true;
+        (chat, server)
+    }
+
+    ///
+    /// Takes the next connection through its handshake and registration.
+    ///
+    async fn accept(server: &mut MemoryServer) -> MemoryConnection {
+        let mut conn = tokio::time::timeout(TIMEOUT, server.accept())
+            .await
+            .expect("the chat should connect")
+            .unwrap();
+        // PASS, NICK, JOIN and CAP REQ
+        for _ in 0..4 {
+            conn.recv_line().await.unwrap();
+        }
+        conn.send_frame(":tmi.twitch.tv CAP * ACK :twitch.tv/tags twitch.tv/commands");
+        conn.send_frame(":tmi.twitch.tv 001 justinfan354678 :Welcome, GLHF!");
+        conn
+    }
+
+    async fn next_event(chat: &mut Chat) -> ChatEvent {
+        tokio::time::timeout(TIMEOUT, chat.receive_event())
+            .await
+            .expect("an event should come in")
+    }
+
+    async fn next_message(chat: &mut Chat) -> ChatMessage {
+        tokio::time::timeout(TIMEOUT, chat.receive())
+            .await
+            .expect("a message should come in")
+    }
+
+    #[tokio::test]
+    async fn canned_frames_come_through_and_a_closed_transport_reconnects() {
+        let (mut chat, mut server) = memory_chat();
+        chat.join("channel");
+        let conn = accept(&mut server).await;
+
+        conn.send_frame(PRIVMSG);
+        let message = next_message(&mut chat).await;
+        assert_eq!(message.author, "Viewer");
+        assert_eq!(message.login.as_deref(), Some("viewer"));
+        assert_eq!(message.message, "hello chat");
+        assert_eq!(message.channel.as_deref(), Some("channel"));
+        assert_eq!(message.color.as_deref(), Some("#1E90FF"));
+        assert_eq!(message.room_id.as_deref(), Some("1337"));
+
+        conn.send_frame(USERNOTICE);
+        let sub = loop {
+            if let ChatEvent::Sub(sub) = next_event(&mut chat).await {
+                break sub;
+            }
         };
+        assert_eq!(sub.user, "Subber");
+        assert_eq!(sub.user_login, "subber");
+        assert!(!sub.is_resub);
+        assert_eq!(sub.sub_plan_name, "Channel Subscription (channel)");
+        assert_eq!(sub.cumulative_months, 1);
+        assert_eq!(sub.message.as_deref(), Some("first sub"));
 
-        let message = str.splitn(3, ':').last().unwrap().to_string();
+        // several lines in one frame are each handled
+        let frame = [
+            ":first!first@first.tmi.twitch.tv PRIVMSG #channel :one",
+            ":second!second@second.tmi.twitch.tv PRIVMSG #channel :two",
+            "",
+        ]
+        .join("\r\n");
+        conn.send_frame(&frame);
+        assert_eq!(next_message(&mut chat).await.message, "one");
+        assert_eq!(next_message(&mut chat).await.message, "two");
 
-        if let (Some(author), message) = (author, message) {
-            Some(ChatMessage {
-                author,
-                color: None,
-                message,
-            })
-        } else {
-            None
+        drop(conn);
+        loop {
+            if let ChatEvent::Disconnected(reason) = next_event(&mut chat).await {
+                assert!(matches!(reason, DisconnectReason::Connection(_)));
+                break;
+            }
         }
+        let conn = accept(&mut server).await;
+        assert!(chat.is_running());
+
+        conn.send_frame(PRIVMSG);
+        assert_eq!(next_message(&mut chat).await.message, "hello chat");
+        chat.leave().await;
     }
 
-    pub fn format_user_message_with_tags(str: &str) -> Option<ChatMessage> {
-        let str = str.split_once("\r\n").unwrap().0;
+    #[tokio::test]
+    async fn a_message_queued_during_a_reconnect_is_sent_on_the_new_connection() {
+        let (mut chat, mut server) = memory_chat();
+        chat.join("channel");
+        let mut first = accept(&mut server).await;
 
-        let (tags, _author_info, message) = {
-            let (tags, tail) = match str.split_once(" :") {
-                Some((tags, tail)) => (tags, tail),
-                None => return None,
-            };
+        first.send_frame(":tmi.twitch.tv RECONNECT");
+        loop {
+            if let ChatEvent::Disconnected(reason) = next_event(&mut chat).await {
+                assert!(matches!(reason, DisconnectReason::Reconnect));
+                break;
+            }
+        }
+        // the first connection ended before `Disconnected` was sent, so this waits for the next
+        chat.send("queued in the gap".to_string()).await.unwrap();
 
-            let (author_info, message) = match tail.split_once(" :") {
-                Some((author_info, message)) => (author_info, message),
-                None => return None,
-            };
-            (tags, author_info, message)
+        let mut second = accept(&mut server).await;
+        let line = tokio::time::timeout(TIMEOUT, second.recv_line())
+            .await
+            .unwrap();
+        assert_eq!(line.as_deref(), Some("PRIVMSG #channel :queued in the gap"));
+        // nothing was written to the old connection after it was told to go away
+        assert_eq!(first.recv_line().await, None);
+        chat.leave().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn receive_next_to_a_timeout_loses_nothing_under_load() {
+        const MESSAGES: usize = 500;
+        let (mut chat, mut server) = memory_chat();
+        chat.join("channel");
+        let conn = accept(&mut server).await;
+
+        // bursts with pauses in between, so both branches of the select keep winning
+        let feeder = tokio::spawn(async move {
+            for i in 0..MESSAGES {
+                conn.send_frame(&format!(
+                    ":viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #channel :{}",
+                    i
+                ));
+                if i % 50 == 49 {
+                    tokio::time::sleep(Duration::from_millis(25)).await;
+                }
+            }
+            conn
+        });
+
+        let mut received: Vec<usize> = vec![];
+        let mut timeouts = 0;
+        while received.len() < MESSAGES {
+            tokio::select! {
+                message = chat.receive() => received.push(message.message.parse().unwrap()),
+                _ = tokio::time::sleep(Duration::from_millis(10)) => timeouts += 1,
+            }
+            assert!(timeouts < 1000, "only {} messages came in", received.len());
+        }
+        assert!(
+            timeouts > 0,
+            "the timeout should have won in between the bursts"
+        );
+        assert_eq!(received, (0..MESSAGES).collect::<Vec<_>>());
+
+        let _conn = feeder.await.unwrap();
+        chat.leave().await;
+    }
+
+    async fn handshake_lines(config: &ConnectConfig) -> (bool, Vec<String>) {
+        let (connector, mut server) = memory::pair();
+        let mut transport = connector.connect("ws://memory").await.unwrap();
+        let mut conn = server.accept().await.unwrap();
+
+        let cap_requested = perform_handshake(transport.as_mut(), config).await.unwrap();
+        drop(transport);
+        let mut lines = vec![];
+        while let Some(line) = conn.recv_line().await {
+            lines.push(line);
+        }
+        (cap_requested, lines)
+    }
+
+    #[tokio::test]
+    async fn handshake_sends_pass_nick_join_and_cap_req() {
+        let config = ConnectConfig {
+            channel: Some("#Channel".to_string()),
+            oauth: Some("abc123".to_string()),
+            nick: Some("BotNick".to_string()),
+            capabilities: CapabilitySet::default(),
+            ..Default::default()
         };
+        let (cap_requested, lines) = handshake_lines(&config).await;
+        assert!(cap_requested);
+        assert_eq!(
+            lines,
+            [
+                "PASS oauth:abc123",
+                "NICK botnick\n\r",
+                "JOIN #channel\n\r",
+                "CAP REQ :twitch.tv/tags",
+            ]
+        );
+    }
 
-        let tags = parse_tags(tags);
+    #[tokio::test]
+    async fn anonymous_handshake_without_capabilities_sends_no_cap_req() {
+        let config = ConnectConfig {
+            channel: Some("channel".to_string()),
+            capabilities: CapabilitySet {
+                tags: false,
+                commands: false,
+                membership: false,
+            },
+            ..Default::default()
+        };
+        let (cap_requested, lines) = handshake_lines(&config).await;
+        assert!(!cap_requested);
+        assert_eq!(
+            lines,
+            [
+                "PASS oauth:blah",
+                "NICK justinfan354678\n\r",
+                "JOIN #channel\n\r"
+            ]
+        );
+    }
 
-        let author = match tags.get("display-name").as_mut() {
-            Some(author) => author.to_string(),
-            None => return None,
+    #[tokio::test]
+    async fn reconnect_revives_a_dead_supervisor() {
+        let (mut chat, mut server) = memory_chat();
+        chat.join("channel");
+        let mut first = accept(&mut server).await;
+        assert!(chat.is_running());
+
+        chat.controller.abort_supervisor();
+        tokio::time::timeout(TIMEOUT, async {
+            while chat.is_running() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the aborted supervisor should finish");
+
+        chat.reconnect();
+        assert!(chat.is_running());
+        let second = accept(&mut server).await;
+        // the connection of the dead supervisor leaves once its queue is replaced
+        let part = tokio::time::timeout(TIMEOUT, first.recv_line())
+            .await
+            .unwrap();
+        assert_eq!(part.as_deref(), Some("PART #channel"));
+
+        second.send_frame(PRIVMSG);
+        assert_eq!(next_message(&mut chat).await.message, "hello chat");
+        chat.leave().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn init_join_and_receive_after_a_handshake_timeout() {
+        let dir = std::env::temp_dir().join(format!("ttvy_core-e2e-{}", std::process::id()));
+        let path = dir.join("state.json");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            &path,
+            r#"{"channel":"channel","nick":"BotNick","oauth":"abc123",
+                "handshake_timeout_ms":1000,"reconnect_jitter_ms":0}"#,
+        )
+        .unwrap();
+
+        let (connector, mut server) = memory::pair();
+        let mut chat = Chat::with_connector(connector);
+        chat.init_with(&FileStore::new(&path)).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        let channel = chat
+            .config
+            .channel
+            .clone()
+            .expect("the channel should be loaded");
+        chat.join(&channel);
+
+        // a server that never acknowledges the capabilities is given up on
+        let mut silent = server.accept().await.unwrap();
+        let mut handshake = vec![];
+        for _ in 0..4 {
+            handshake.push(silent.recv_line().await.unwrap());
+        }
+        assert_eq!(handshake[0], "PASS oauth:abc123");
+        assert_eq!(handshake[1], "NICK botnick\n\r");
+        loop {
+            if let ChatEvent::Disconnected(reason) = next_event(&mut chat).await {
+                assert!(matches!(reason, DisconnectReason::HandshakeTimeout));
+                break;
+            }
+        }
+
+        let conn = accept(&mut server).await;
+        conn.send_frame(PRIVMSG);
+        let message = next_message(&mut chat).await;
+        assert_eq!(message.author, "Viewer");
+        assert_eq!(message.message, "hello chat");
+        chat.leave().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_supervisor_backs_off_on_the_paused_clock() {
+        let (mut chat, mut server) = memory_chat();
+        chat.join("channel");
+
+        let mut last = Instant::now();
+        let mut waits = vec![];
+        for _ in 0..5 {
+            // connections that never register count as reconnects in a row
+            let mut conn = server.accept().await.unwrap();
+            waits.push(Instant::now().duration_since(last).as_secs());
+            conn.recv_line().await.unwrap();
+            drop(conn);
+            last = Instant::now();
+        }
+        assert_eq!(waits, [0, 0, 1, 2, 4]);
+        chat.leave().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_holds_back_the_events_already_waiting() {
+        let (mut chat, mut server) = memory_chat();
+        chat.join("channel");
+        let conn = accept(&mut server).await;
+        conn.send_frame(":viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #channel :before");
+        // the clock only moves on once it was handed to the output
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        chat.pause();
+        conn.send_frame(":viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #channel :during");
+        let held = tokio::time::timeout(Duration::from_secs(1), chat.receive_event()).await;
+        assert!(
+            held.is_err(),
+            "nothing should come in while paused: {:?}",
+            held
+        );
+
+        chat.resume();
+        assert_eq!(next_message(&mut chat).await.message, "before");
+        assert_eq!(next_message(&mut chat).await.message, "during");
+        chat.leave().await;
+    }
+
+    #[tokio::test]
+    async fn a_rejected_token_stops_for_a_reauth() {
+        let (mut chat, mut server) = memory_chat();
+        chat.join("channel");
+        let mut conn = server.accept().await.unwrap();
+        conn.recv_line().await.unwrap();
+        conn.send_frame(":tmi.twitch.tv NOTICE * :Login authentication failed");
+
+        let reason = loop {
+            if let ChatEvent::Disconnected(reason) = next_event(&mut chat).await {
+                break reason;
+            }
         };
+        match reason {
+            DisconnectReason::Notice { msg_id, action } => {
+                assert_eq!(msg_id, parse::LOGIN_FAILED);
+                assert_eq!(action, NoticeAction::Reauth);
+            }
+            reason => panic!("expected a NOTICE disconnect, got {:?}", reason),
+        }
+        tokio::time::timeout(TIMEOUT, async {
+            while chat.is_running() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the supervisor should stop instead of reconnecting");
+    }
+
+    async fn disconnect_reason(chat: &mut Chat) -> DisconnectReason {
+        loop {
+            if let ChatEvent::Disconnected(reason) = next_event(chat).await {
+                return reason;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn only_a_channel_notice_moves_on_to_the_fallback_channel() {
+        let (mut chat, mut server) = memory_chat();
+        chat.config.channels = vec!["fallback".to_string(), "other".to_string()];
+        chat.join("channel");
+        let banned = accept(&mut server).await;
+        banned.send_frame(
+            "@msg-id=msg_banned :tmi.twitch.tv NOTICE #channel :You are permanently banned.",
+        );
+        assert!(matches!(
+            disconnect_reason(&mut chat).await,
+            DisconnectReason::Notice {
+                action: NoticeAction::Stop,
+                ..
+            }
+        ));
 
-        let color = tags.get("color").as_mut().map(|color| color.to_string());
+        let mut fallback = server.accept().await.unwrap();
+        let mut handshake = vec![];
+        for _ in 0..4 {
+            handshake.push(fallback.recv_line().await.unwrap());
+        }
+        assert_eq!(handshake[2], "JOIN #fallback\n\r");
 
-        Some(ChatMessage {
-            author,
-            color,
-            message: message.to_owned(),
+        // an account that can't chat anywhere doesn't try the other channels
+        fallback.send_frame("@msg-id=tos_ban :tmi.twitch.tv NOTICE #fallback :You are banned.");
+        assert!(matches!(
+            disconnect_reason(&mut chat).await,
+            DisconnectReason::Notice {
+                action: NoticeAction::Stop,
+                ..
+            }
+        ));
+        tokio::time::timeout(TIMEOUT, async {
+            while chat.is_running() {
+                tokio::task::yield_now().await;
+            }
         })
+        .await
+        .expect("the supervisor should stop");
+        let reconnect = tokio::time::timeout(Duration::from_millis(100), server.accept()).await;
+        assert!(reconnect.is_err(), "no other channel should be tried");
+        drop(fallback);
     }
 
-    fn parse_tags(tags: &str) -> HashMap<&str, &str> {
-        tags.split(';')
-            .filter_map(|pair| pair.split_once('='))
-            .collect()
+    #[tokio::test]
+    async fn a_switch_waits_for_the_low_priority_messages_before_it() {
+        let (mut chat, mut server) = memory_chat();
+        chat.join("channel");
+        let mut conn = accept(&mut server).await;
+        tokio::time::timeout(TIMEOUT, chat.await_connected())
+            .await
+            .unwrap();
+
+        // the first message starts the send spacing, so the rest is queued up behind it
+        chat.send("first".to_string()).await.unwrap();
+        chat.send_priority("low".to_string(), Priority::Low)
+            .await
+            .unwrap();
+        chat.switch_channel("other").await;
+        chat.send("after".to_string()).await.unwrap();
+
+        let mut lines = vec![];
+        for _ in 0..5 {
+            let line = tokio::time::timeout(TIMEOUT, conn.recv_line())
+                .await
+                .unwrap();
+            lines.push(line.unwrap());
+        }
+        assert_eq!(
+            lines,
+            [
+                "PRIVMSG #channel :first",
+                "PRIVMSG #channel :low",
+                "PART #channel",
+                "JOIN #other",
+                "PRIVMSG #other :after",
+            ]
+        );
+        chat.leave().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_batch_keeps_its_delay_behind_a_backlog() {
+        let (mut chat, mut server) = memory_chat();
+        chat.join("channel");
+        let mut conn = accept(&mut server).await;
+
+        let start = Instant::now();
+        chat.send("first".to_string()).await.unwrap();
+        chat.send("backlog".to_string()).await.unwrap();
+        let lines = ["one", "two", "three"].map(|line| line.to_string());
+        chat.send_batch(lines.to_vec(), Duration::from_secs(2))
+            .await
+            .unwrap();
+        chat.send("after".to_string()).await.unwrap();
+
+        let mut sent = vec![];
+        for _ in 0..6 {
+            let line = conn.recv_line().await.unwrap();
+            sent.push((line, start.elapsed().as_millis()));
+        }
+        let expected = [
+            ("PRIVMSG #channel :first", 0),
+            ("PRIVMSG #channel :backlog", 250),
+            ("PRIVMSG #channel :one", 500),
+            ("PRIVMSG #channel :two", 2500),
+            ("PRIVMSG #channel :three", 4500),
+            // the last line of a batch is followed by the usual spacing
+            ("PRIVMSG #channel :after", 4750),
+        ]
+        .map(|(line, at)| (line.to_string(), at));
+        assert_eq!(sent, expected);
+        chat.leave().await;
+    }
+    #[tokio::test]
+    async fn a_sender_queues_from_another_task() {
+        let (mut chat, mut server) = memory_chat();
+        assert!(matches!(chat.sender(), Err(SendError::NotConnected)));
+
+        chat.join("channel");
+        let mut conn = accept(&mut server).await;
+        let sender = chat.sender().unwrap();
+        tokio::spawn(async move { sender.send("from a task".to_string()).await })
+            .await
+            .unwrap()
+            .unwrap();
+        let line = tokio::time::timeout(TIMEOUT, conn.recv_line())
+            .await
+            .unwrap();
+        assert_eq!(line.unwrap(), "PRIVMSG #channel :from a task");
+        chat.leave().await;
+    }
+
+    #[tokio::test]
+    async fn a_message_to_another_channel_counts_towards_its_slow_mode() {
+        let (mut chat, mut server) = memory_chat();
+        chat.join("channel");
+        let mut conn = accept(&mut server).await;
+        conn.send_frame("@slow=30 :tmi.twitch.tv ROOMSTATE #other");
+        while !matches!(next_event(&mut chat).await, ChatEvent::RoomState(_)) {}
+        assert_eq!(chat.slow_mode_remaining("other"), None);
+
+        let target = Target::Channel("Other".to_string());
+        chat.send_to_target(target, "hi".to_string()).await.unwrap();
+        let line = tokio::time::timeout(TIMEOUT, conn.recv_line())
+            .await
+            .unwrap();
+        assert_eq!(line.unwrap(), "PRIVMSG #other :hi");
+        assert!(chat.slow_mode_remaining("other").is_some());
+        assert_eq!(chat.slow_mode_remaining("channel"), None);
+        chat.leave().await;
     }
 }
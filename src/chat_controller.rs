@@ -1,44 +1,512 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::oneshot;
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, watch, Mutex, Notify};
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
-use super::chat::{connect, ChatMessage};
-use super::config::Config;
+use super::chat::{
+    connect, ChannelStatus, ChatEvent, ChatMessage, ConnectionState, DisconnectReason,
+    DroppedEvents, ModAction, RoomState, SelfUser, Status, TimelineEntry,
+};
+use super::config::{
+    redact_oauth, AntiDupeMode, CapabilitySet, Config, SendBehavior, DEFAULT_HANDSHAKE_TIMEOUT_MS,
+    DEFAULT_RECONNECT_JITTER_MS, DEFAULT_SEND_SPACING_MS,
+};
+use super::error::SendError;
+use super::history::HistoryFile;
+use super::outgoing::{MessageSender, Outgoing, OutgoingQueue, OutgoingSender, Priority, Target};
+use super::policy::{NoticeAction, NoticePolicy, CHANNEL_MSG_IDS};
+use super::transport::{Connector, WebSocketConnector};
 
-#[derive(Debug, Clone, Default)]
+#[derive(Clone, Default)]
 pub struct ConnectConfig {
     pub channel: Option<String>,
+    pub channels: Vec<String>,
     pub oauth: Option<String>,
     pub nick: Option<String>,
+    pub dedup: Option<usize>,
+    pub dry_run: bool,
+    pub notice_policy: NoticePolicy,
+    pub endpoint: String,
+    pub send_spacing: Duration,
+    pub antidupe: AntiDupeMode,
+    pub lossy_delivery: bool,
+    pub handshake_timeout: Duration,
+    pub reconnect_jitter: Duration,
+    pub raw_events: bool,
+    pub raw_outgoing: bool,
+    pub trim_trailing_whitespace: bool,
+    pub capabilities: CapabilitySet,
+    pub ignored_prefixes: Vec<String>,
+    pub status_interval: Option<Duration>,
+    pub mod_view: bool,
+    pub auto_rejoin: Option<u32>,
+    pub history: Option<HistoryFile>,
+}
+
+impl fmt::Debug for ConnectConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectConfig")
+            .field("channel", &self.channel)
+            .field("channels", &self.channels)
+            .field("oauth", &redact_oauth(&self.oauth))
+            .field("nick", &self.nick)
+            .field("dedup", &self.dedup)
+            .field("dry_run", &self.dry_run)
+            .field("notice_policy", &self.notice_policy)
+            .field("endpoint", &self.endpoint)
+            .field("send_spacing", &self.send_spacing)
+            .field("antidupe", &self.antidupe)
+            .field("lossy_delivery", &self.lossy_delivery)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("reconnect_jitter", &self.reconnect_jitter)
+            .field("raw_events", &self.raw_events)
+            .field("raw_outgoing", &self.raw_outgoing)
+            .field("trim_trailing_whitespace", &self.trim_trailing_whitespace)
+            .field("capabilities", &self.capabilities)
+            .field("ignored_prefixes", &self.ignored_prefixes)
+            .field("status_interval", &self.status_interval)
+            .field("mod_view", &self.mod_view)
+            .field("auto_rejoin", &self.auto_rejoin)
+            .field("history", &self.history)
+            .finish()
+    }
+}
+
+impl ConnectConfig {
+    ///
+    /// `channel` followed by the fallback `channels`, in the order they are tried.
+    ///
+    pub fn candidate_channels(&self) -> Vec<String> {
+        let mut candidates: Vec<String> = self.channel.iter().cloned().collect();
+        for channel in &self.channels {
+            if !candidates.contains(channel) {
+                candidates.push(channel.clone());
+            }
+        }
+        candidates
+    }
 }
 
 impl From<Config> for ConnectConfig {
     fn from(value: Config) -> Self {
+        let endpoint = value.endpoint().to_string();
+        let history = value
+            .history_size
+            .filter(|size| *size > 0)
+            .and_then(|size| match value.history_file() {
+                Ok(path) => Some(HistoryFile { path, size }),
+                Err(e) => {
+                    eprintln!("History not kept: {}", e);
+                    None
+                }
+            });
         let Config {
             channel,
+            channels,
             oauth,
             nick,
+            dedup,
+            dry_run,
+            notice_policy,
+            send_spacing_ms,
+            antidupe,
+            lossy_delivery,
+            handshake_timeout_ms,
+            reconnect_jitter_ms,
+            raw_events,
+            raw_outgoing,
+            trim_trailing_whitespace,
+            capabilities,
+            ignored_prefixes,
+            status_interval_ms,
+            mod_view,
+            auto_rejoin,
             ..
         } = value;
 
         Self {
             channel,
+            channels,
             oauth,
             nick,
+            dedup,
+            dry_run,
+            notice_policy,
+            endpoint,
+            send_spacing: Duration::from_millis(send_spacing_ms.unwrap_or(DEFAULT_SEND_SPACING_MS)),
+            antidupe,
+            lossy_delivery,
+            handshake_timeout: Duration::from_millis(
+                handshake_timeout_ms.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT_MS),
+            ),
+            reconnect_jitter: Duration::from_millis(
+                reconnect_jitter_ms.unwrap_or(DEFAULT_RECONNECT_JITTER_MS),
+            ),
+            raw_events,
+            raw_outgoing,
+            trim_trailing_whitespace,
+            capabilities,
+            ignored_prefixes,
+            status_interval: status_interval_ms
+                .filter(|interval| *interval > 0)
+                .map(Duration::from_millis),
+            mod_view,
+            auto_rejoin,
+            history,
+        }
+    }
+}
+
+///
+/// Events held back while delivery is paused, bounded like a ring buffer.
+///
+const PAUSE_BUFFER_CAPACITY: usize = 1024;
+
+///
+/// Lines kept for `take_dry_run_messages`, the oldest are dropped once it is full.
+///
+const DRY_RUN_LOG_CAPACITY: usize = 1024;
+
+#[derive(Debug, Default)]
+struct Delivery {
+    paused: bool,
+    buffer: VecDeque<ChatEvent>,
+    dropped: DropCounter,
+}
+
+const DROP_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+///
+/// Counts dropped events between two warnings, so a slow consumer gets one summary
+/// per `DROP_WARNING_INTERVAL` rather than a warning per event.
+/// Events the consumer had no room for and the oldest ones pushed out of a full pause buffer
+/// are counted together.
+///
+#[derive(Debug, Default)]
+struct DropCounter {
+    count: u64,
+    first_drop: Option<Instant>,
+    last_warning: Option<Instant>,
+}
+
+impl DropCounter {
+    fn record(&mut self) -> Option<DroppedEvents> {
+        let now = Instant::now();
+        self.count += 1;
+        self.first_drop.get_or_insert(now);
+        match self.last_warning {
+            Some(at) if now.duration_since(at) < DROP_WARNING_INTERVAL => None,
+            _ => self.warning(now),
+        }
+    }
+
+    ///
+    /// When the drops counted since the last warning are due, if there are any.
+    ///
+    fn flush_at(&self) -> Option<Instant> {
+        (self.count > 0).then_some(self.last_warning? + DROP_WARNING_INTERVAL)
+    }
+
+    ///
+    /// The drops counted since the last warning once they are due, so the end of a burst
+    /// is reported even if nothing is dropped after it.
+    ///
+    fn flush(&mut self) -> Option<DroppedEvents> {
+        let now = Instant::now();
+        match self.flush_at() {
+            Some(at) if at <= now => self.warning(now),
+            _ => None,
+        }
+    }
+
+    fn warning(&mut self, now: Instant) -> Option<DroppedEvents> {
+        let window = now.duration_since(self.first_drop.take()?);
+        self.last_warning = Some(now);
+        Some(DroppedEvents {
+            count: std::mem::take(&mut self.count),
+            window,
+        })
+    }
+}
+
+///
+/// Remembers the ids of the last `window` messages, kept across reconnects.
+///
+#[derive(Debug, Default)]
+struct Dedup {
+    window: usize,
+    order: VecDeque<String>,
+    seen: HashSet<String>,
+}
+
+impl Dedup {
+    fn new(window: usize) -> Self {
+        Self {
+            window,
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Returns `false` if the event is a message whose id was already seen.
+    ///
+    fn check(&mut self, event: &ChatEvent) -> bool {
+        let ChatEvent::Message(msg) = event else {
+            return true;
+        };
+        let Some(id) = msg.id.as_ref() else {
+            return true;
+        };
+
+        if self.seen.contains(id) {
+            return false;
+        }
+
+        if self.order.len() == self.window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back(id.clone());
+        self.seen.insert(id.clone());
+        true
+    }
+}
+
+const MESSAGE_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+///
+/// Moderation actions kept per channel, dropping the oldest.
+///
+const MOD_ACTION_CAPACITY: usize = 256;
+
+///
+/// Timeline entries kept per channel with `mod_view`, dropping the oldest.
+///
+const TIMELINE_CAPACITY: usize = 256;
+
+#[derive(Debug, Default)]
+pub(super) struct ChannelState {
+    pub joined: bool,
+    pub room_state: Option<RoomState>,
+    recent_messages: VecDeque<Instant>,
+    last_sent: Option<Instant>,
+    mod_actions: VecDeque<ModAction>,
+    timeline: VecDeque<TimelineEntry>,
+    history: VecDeque<ChatMessage>,
+}
+
+impl ChannelState {
+    pub fn record_mod_action(&mut self, action: ModAction) {
+        if self.mod_actions.len() == MOD_ACTION_CAPACITY {
+            self.mod_actions.pop_front();
+        }
+        self.mod_actions.push_back(action);
+    }
+
+    pub fn record_timeline_message(&mut self, message: ChatMessage) {
+        self.push_timeline(TimelineEntry::Message {
+            message,
+            removed: None,
+        });
+    }
+
+    ///
+    /// Marks the messages `action` removed, a CLEARMSG takes the place of its message
+    /// and is only added on its own if the message is no longer kept.
+    ///
+    pub fn record_timeline_action(&mut self, action: &ModAction) {
+        let mut deleted = false;
+        for entry in self.timeline.iter_mut() {
+            let TimelineEntry::Message { message, removed } = entry else {
+                continue;
+            };
+            let matches = match action {
+                ModAction::ClearMsg(clear_msg) => {
+                    message.id.as_deref() == Some(clear_msg.target_msg_id.as_str())
+                }
+                ModAction::ClearChat(clear_chat) => match clear_chat.target_login.as_ref() {
+                    Some(login) => match message.login.as_ref() {
+                        Some(author) => author == login,
+                        None => message.author.eq_ignore_ascii_case(login),
+                    },
+                    None => true,
+                },
+            };
+            if matches && removed.is_none() {
+                *removed = Some(action.clone());
+                deleted = true;
+            }
+        }
+
+        if !(deleted && matches!(action, ModAction::ClearMsg(_))) {
+            self.push_timeline(TimelineEntry::ModAction(action.clone()));
+        }
+    }
+
+    fn push_timeline(&mut self, entry: TimelineEntry) {
+        if self.timeline.len() == TIMELINE_CAPACITY {
+            self.timeline.pop_front();
+        }
+        self.timeline.push_back(entry);
+    }
+
+    pub fn record_history(&mut self, message: ChatMessage, size: usize) {
+        while self.history.len() >= size {
+            self.history.pop_front();
+        }
+        self.history.push_back(message);
+    }
+
+    pub fn record_send(&mut self) {
+        self.last_sent = Some(Instant::now());
+    }
+
+    ///
+    /// Time until slow mode lets our next message through, if it would be rejected now.
+    ///
+    fn slow_mode_remaining(&self) -> Option<Duration> {
+        let slow = Duration::from_secs(self.room_state.as_ref()?.slow?);
+        let remaining = slow.checked_sub(self.last_sent?.elapsed())?;
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    pub fn record_message(&mut self) {
+        let now = Instant::now();
+        self.recent_messages.push_back(now);
+        while self
+            .recent_messages
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > MESSAGE_RATE_WINDOW)
+        {
+            self.recent_messages.pop_front();
+        }
+    }
+
+    fn status(&self, channel: &str) -> ChannelStatus {
+        let now = Instant::now();
+        ChannelStatus {
+            channel: channel.to_string(),
+            joined: self.joined,
+            room_state: self.room_state.clone(),
+            messages_per_minute: self
+                .recent_messages
+                .iter()
+                .filter(|at| now.duration_since(**at) <= MESSAGE_RATE_WINDOW)
+                .count(),
         }
     }
 }
 
+///
+/// State written by the connection task and read through the `Controller`.
+///
+#[derive(Debug)]
+pub(super) struct Shared {
+    pub dry_run_log: std::sync::Mutex<VecDeque<String>>,
+    pub self_user: std::sync::Mutex<Option<SelfUser>>,
+    pub channels: std::sync::Mutex<HashMap<String, ChannelState>>,
+    /// Every received line, only fed when `raw_events` is enabled
+    pub raw_lines: broadcast::Sender<String>,
+    /// Every sent line, only fed when `raw_outgoing` is enabled
+    pub sent_lines: broadcast::Sender<String>,
+    pub connection_state: watch::Sender<ConnectionState>,
+    /// Set by `switch_channel`, until the next `join`
+    pub switched_channel: std::sync::Mutex<Option<String>>,
+    /// The newest message per channel, updated by the proxy worker
+    pub latest: std::sync::Mutex<HashMap<String, watch::Sender<Option<ChatMessage>>>>,
+    /// When the last frame was received, or the chat was created before any
+    pub last_received: std::sync::Mutex<Instant>,
+    /// Set once the saved history was loaded, so it is neither loaded twice
+    /// nor saved over before it was read
+    pub history_loaded: AtomicBool,
+}
+
+impl Shared {
+    pub fn log_dry_run(&self, line: String) {
+        let mut log = self.dry_run_log.lock().unwrap();
+        if log.len() == DRY_RUN_LOG_CAPACITY {
+            log.pop_front();
+        }
+        log.push_back(line);
+    }
+
+    fn channels_snapshot(&self) -> Vec<ChannelStatus> {
+        let channels = self.channels.lock().unwrap();
+        let mut snapshot: Vec<ChannelStatus> = channels
+            .iter()
+            .map(|(channel, state)| state.status(channel))
+            .collect();
+        snapshot.sort_by(|a, b| a.channel.cmp(&b.channel));
+        snapshot
+    }
+
+    fn status(&self) -> Status {
+        let channels = self.channels_snapshot();
+        Status {
+            connection_state: *self.connection_state.borrow(),
+            idle: self.last_received.lock().unwrap().elapsed(),
+            messages_per_minute: channels.iter().map(|c| c.messages_per_minute).sum(),
+            channels,
+        }
+    }
+}
+
+/// Outgoing messages that can wait to be sent, including the ones held for the next `join`
+const OUTGOING_CAPACITY: usize = 128;
+
+/// Events a subscriber can fall behind by before it skips ahead
+const SUBSCRIPTION_CAPACITY: usize = 256;
+
+impl Default for Shared {
+    fn default() -> Self {
+        Self {
+            dry_run_log: Default::default(),
+            self_user: Default::default(),
+            channels: Default::default(),
+            raw_lines: broadcast::channel(SUBSCRIPTION_CAPACITY).0,
+            sent_lines: broadcast::channel(SUBSCRIPTION_CAPACITY).0,
+            connection_state: watch::channel(ConnectionState::Disconnected).0,
+            switched_channel: Default::default(),
+            latest: Default::default(),
+            last_received: std::sync::Mutex::new(Instant::now()),
+            history_loaded: AtomicBool::new(false),
+        }
+    }
+}
+
+/// How long `close` waits for the connection to PART before cutting it off
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
 #[derive(Debug)]
 pub struct Controller {
-    proxy_tx: Sender<ChatMessage>,
-    proxy_rx: Option<Receiver<ChatMessage>>,
-    websocket_tx: Arc<Mutex<Option<Sender<String>>>>,
+    proxy_tx: Sender<ChatEvent>,
+    proxy_rx: Option<Receiver<ChatEvent>>,
+    events: broadcast::Sender<ChatEvent>,
+    websocket_tx: Arc<std::sync::Mutex<Option<OutgoingSender>>>,
+    /// Messages sent with `SendBehavior::Queue` while not connected, for the next `join`
+    pending_sends: Arc<std::sync::Mutex<VecDeque<(Priority, Outgoing)>>>,
     handle: Option<JoinHandle<()>>,
-    chat_shutdown_tx: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    chat_shutdown_tx: Arc<std::sync::Mutex<Option<oneshot::Sender<()>>>>,
+    delivery: Arc<std::sync::Mutex<Delivery>>,
+    resumed: Arc<Notify>,
+    shared: Arc<Shared>,
+    endpoint: Option<String>,
+    /// Where the history of the last `join` is saved, if it is kept
+    history: Option<HistoryFile>,
+    connector: Arc<dyn Connector>,
+    runtime: Option<Handle>,
 }
 
 impl Default for Controller {
@@ -49,50 +517,342 @@ impl Default for Controller {
 
 impl Controller {
     pub fn new() -> Self {
-        let (tx, rx) = channel::<ChatMessage>(128);
+        let (tx, rx) = channel::<ChatEvent>(128);
 
         Self {
             proxy_tx: tx,
             proxy_rx: Some(rx),
-            websocket_tx: Arc::new(Mutex::new(None)),
+            events: broadcast::channel(SUBSCRIPTION_CAPACITY).0,
+            websocket_tx: Arc::new(std::sync::Mutex::new(None)),
+            pending_sends: Default::default(),
             handle: None,
-            chat_shutdown_tx: Arc::new(Mutex::new(None)),
+            chat_shutdown_tx: Arc::new(std::sync::Mutex::new(None)),
+            delivery: Arc::new(std::sync::Mutex::new(Delivery::default())),
+            resumed: Arc::new(Notify::new()),
+            shared: Arc::new(Shared::default()),
+            endpoint: None,
+            history: None,
+            connector: Arc::new(WebSocketConnector),
+            runtime: None,
         }
     }
 
-    pub async fn send(&self, chat_message: String) {
-        let lock = self.websocket_tx.lock().await;
-        if let Some(tx) = lock.as_ref() {
-            let _ = tx.send(chat_message).await;
+    pub async fn send(
+        &self,
+        target: Option<Target>,
+        text: String,
+        spacing: Duration,
+        priority: Priority,
+        behavior: SendBehavior,
+    ) -> Result<(), SendError> {
+        let msg = (
+            priority,
+            Outgoing::Message {
+                target,
+                text,
+                spacing,
+            },
+        );
+        // held messages are flushed under the same lock, so they go before any sent after
+        let tx = {
+            let lock = self.websocket_tx.lock().unwrap();
+            match (lock.as_ref(), behavior) {
+                (Some(tx), _) => tx.clone(),
+                (None, SendBehavior::Drop) => return Ok(()),
+                (None, SendBehavior::Error) => return Err(SendError::NotConnected),
+                (None, SendBehavior::Queue(capacity)) => {
+                    let mut pending = self.pending_sends.lock().unwrap();
+                    if pending.len() >= capacity.min(OUTGOING_CAPACITY) {
+                        return Err(SendError::QueueFull);
+                    }
+                    pending.push_back(msg);
+                    return Ok(());
+                }
+            }
+        };
+        tx.send(msg).await.map_err(|_| SendError::Closed)
+    }
+
+    pub fn sender(&self) -> Option<MessageSender> {
+        self.websocket_tx
+            .lock()
+            .unwrap()
+            .clone()
+            .map(MessageSender::new)
+    }
+
+    ///
+    /// PARTs the joined channel and JOINs `channel` on the current connection,
+    /// once the messages queued before it are sent.
+    ///
+    pub async fn switch_channel(&self, channel: String) -> Result<(), SendError> {
+        let tx = self.websocket_tx.lock().unwrap().clone();
+        match tx {
+            Some(tx) => tx
+                .send((Priority::Normal, Outgoing::SwitchChannel(channel)))
+                .await
+                .map_err(|_| SendError::Closed),
+            None => Err(SendError::NotConnected),
         }
     }
 
     ///
     /// Can only be called once, eg only the first call returns `Some`.
     ///
-    pub fn take_receiver(&mut self) -> Option<Receiver<ChatMessage>> {
+    pub fn take_receiver(&mut self) -> Option<Receiver<ChatEvent>> {
         self.proxy_rx.take()
     }
 
+    ///
+    /// Every event from now on, regardless of `take_receiver` and pauses.
+    ///
+    pub fn subscribe(&self) -> broadcast::Receiver<ChatEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.shared.connection_state.subscribe()
+    }
+
+    pub fn idle_duration(&self) -> Duration {
+        self.shared.last_received.lock().unwrap().elapsed()
+    }
+
+    pub fn latest(&self, channel: &str) -> watch::Receiver<Option<ChatMessage>> {
+        let mut latest = self.shared.latest.lock().unwrap();
+        latest
+            .entry(channel.to_string())
+            .or_insert_with(|| watch::channel(None).0)
+            .subscribe()
+    }
+
+    pub fn subscribe_raw(&self) -> broadcast::Receiver<String> {
+        self.shared.raw_lines.subscribe()
+    }
+
+    pub fn subscribe_sent(&self) -> broadcast::Receiver<String> {
+        self.shared.sent_lines.subscribe()
+    }
+
+    pub fn pause(&self) {
+        self.delivery.lock().unwrap().paused = true;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.delivery.lock().unwrap().paused
+    }
+
+    pub fn resume(&self) {
+        self.delivery.lock().unwrap().paused = false;
+        self.resumed.notify_one();
+    }
+
+    ///
+    /// Pops the oldest event held back by a pause, once delivery has resumed.
+    ///
+    pub fn take_buffered(&self) -> Option<ChatEvent> {
+        let mut delivery = self.delivery.lock().unwrap();
+        if delivery.paused {
+            None
+        } else {
+            delivery.buffer.pop_front()
+        }
+    }
+
+    pub async fn resumed(&self) {
+        self.resumed.notified().await;
+    }
+
+    pub fn take_dry_run_messages(&self) -> Vec<String> {
+        std::mem::take(&mut *self.shared.dry_run_log.lock().unwrap()).into()
+    }
+
+    pub fn self_user(&self) -> Option<SelfUser> {
+        self.shared.self_user.lock().unwrap().clone()
+    }
+
+    ///
+    /// The last `limit` moderation actions in `channel`, oldest first.
+    ///
+    pub fn recent_mod_actions(&self, channel: &str, limit: usize) -> Vec<ModAction> {
+        let channels = self.shared.channels.lock().unwrap();
+        let Some(state) = channels.get(channel) else {
+            return vec![];
+        };
+        let skip = state.mod_actions.len().saturating_sub(limit);
+        state.mod_actions.iter().skip(skip).cloned().collect()
+    }
+
+    ///
+    /// The last `limit` timeline entries of `channel`, oldest first.
+    ///
+    pub fn timeline(&self, channel: &str, limit: usize) -> Vec<TimelineEntry> {
+        let channels = self.shared.channels.lock().unwrap();
+        let Some(state) = channels.get(channel) else {
+            return vec![];
+        };
+        let skip = state.timeline.len().saturating_sub(limit);
+        state.timeline.iter().skip(skip).cloned().collect()
+    }
+
+    ///
+    /// The last `limit` messages of `channel`, oldest first.
+    ///
+    pub fn history(&self, channel: &str, limit: usize) -> Vec<ChatMessage> {
+        let channels = self.shared.channels.lock().unwrap();
+        let Some(state) = channels.get(channel) else {
+            return vec![];
+        };
+        let skip = state.history.len().saturating_sub(limit);
+        state.history.iter().skip(skip).cloned().collect()
+    }
+
+    ///
+    /// Writes the kept history to its file, nothing when it isn't kept
+    /// or the saved one wasn't loaded yet.
+    ///
+    pub async fn save_history(&self) -> Result<(), tokio::io::Error> {
+        let Some(history_file) = self.history.as_ref() else {
+            return Ok(());
+        };
+        if !self.shared.history_loaded.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        let history: HashMap<String, Vec<ChatMessage>> = self
+            .shared
+            .channels
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, state)| !state.history.is_empty())
+            .map(|(channel, state)| (channel.clone(), state.history.iter().cloned().collect()))
+            .collect();
+        history_file.save(&history).await
+    }
+
+    async fn save_history_or_warn(&self) {
+        if let Err(e) = self.save_history().await {
+            eprintln!("Failed to save history: {}", e);
+        }
+    }
+
+    pub fn slow_mode_remaining(&self, channel: &str) -> Option<Duration> {
+        self.shared
+            .channels
+            .lock()
+            .unwrap()
+            .get(channel)?
+            .slow_mode_remaining()
+    }
+
+    pub fn channels_snapshot(&self) -> Vec<ChannelStatus> {
+        self.shared.channels_snapshot()
+    }
+
+    pub fn status(&self) -> Status {
+        self.shared.status()
+    }
+
+    ///
+    /// Used from the next `join` on.
+    ///
+    pub fn set_connector(&mut self, connector: Arc<dyn Connector>) {
+        self.connector = connector;
+    }
+
+    ///
+    /// Spawns the connection tasks on `runtime` instead of the runtime `join` is called from.
+    ///
+    pub fn set_runtime(&mut self, runtime: Handle) {
+        self.runtime = Some(runtime);
+    }
+
+    ///
+    /// The endpoint of the last or current connection.
+    ///
+    pub fn endpoint(&self) -> Option<&str> {
+        self.endpoint.as_deref()
+    }
+
+    ///
+    /// Whether the supervisor task is alive, `false` before the first `join`,
+    /// after `leave` and once it stopped on its own or panicked.
+    ///
+    pub fn is_running(&self) -> bool {
+        self.handle
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    ///
+    /// Kills the supervisor the way a panic would, without any of the cleanup of `leave`.
+    ///
+    #[cfg(test)]
+    pub fn abort_supervisor(&self) {
+        if let Some(handle) = self.handle.as_ref() {
+            handle.abort();
+        }
+    }
+
     pub fn join(&mut self, connect_config: ConnectConfig) {
-        if self.handle.is_none() {
+        self.endpoint = Some(connect_config.endpoint.clone());
+        self.history = connect_config.history.clone();
+        self.shared.switched_channel.lock().unwrap().take();
+        if !self.is_running() {
+            // a supervisor that ended, eg by panicking, is replaced like a missing one
+            self.handle.take();
             self.supervise(connect_config);
         } else {
             let handle = self.handle.as_ref().unwrap();
             handle.abort();
+            // the connection runs in its own task, which outlives the aborted supervisor
+            if let Some(shutdown_tx) = self.chat_shutdown_tx.lock().unwrap().take() {
+                let _ = shutdown_tx.send(());
+            }
 
             self.supervise(connect_config);
         }
     }
 
     pub async fn leave(&mut self) -> &mut Self {
+        self.websocket_tx.lock().unwrap().take();
         if let Some(handle) = self.handle.take() {
-            let mut lock = self.chat_shutdown_tx.lock().await;
+            let mut lock = self.chat_shutdown_tx.lock().unwrap();
             handle.abort();
             if let Some(shutdown_tx) = lock.take() {
                 let _ = shutdown_tx.send(());
             }
         }
+        self.shared
+            .connection_state
+            .send_replace(ConnectionState::Disconnected);
+        self.save_history_or_warn().await;
+
+        self
+    }
+
+    ///
+    /// Like `leave`, but waits for the connection to PART and end instead of aborting it.
+    ///
+    pub async fn close(&mut self) -> &mut Self {
+        self.websocket_tx.lock().unwrap().take();
+        if let Some(mut handle) = self.handle.take() {
+            let shutdown_tx = self.chat_shutdown_tx.lock().unwrap().take();
+            if let Some(shutdown_tx) = shutdown_tx {
+                let _ = shutdown_tx.send(());
+            }
+            if tokio::time::timeout(CLOSE_TIMEOUT, &mut handle)
+                .await
+                .is_err()
+            {
+                handle.abort();
+            }
+        }
+        self.shared
+            .connection_state
+            .send_replace(ConnectionState::Disconnected);
+        self.save_history_or_warn().await;
 
         self
     }
@@ -100,34 +860,146 @@ impl Controller {
     fn supervise(&mut self, connect_config: ConnectConfig) -> &mut Self {
         let controller_websocket_tx = self.websocket_tx.clone();
         let proxy_tx = self.proxy_tx.clone();
+        let events = self.events.clone();
         let shutdown_mutex = self.chat_shutdown_tx.clone();
+        let delivery = self.delivery.clone();
+        let shared = self.shared.clone();
+        let connector = self.connector.clone();
+        let dedup = connect_config
+            .dedup
+            .filter(|window| *window > 0)
+            .map(|window| Arc::new(std::sync::Mutex::new(Dedup::new(window))));
+
+        //setup channel for sending messages over websocket
+        // ttvy_core --> websocket --> (twitch server)
+        // it outlives each connection, so messages sent while reconnecting are kept.
+        // Set up before the supervisor runs, so `send` right after `join` doesn't fail
+        let (websocket_tx, outgoing_rx) = channel::<(Priority, Outgoing)>(OUTGOING_CAPACITY);
+        {
+            let mut websocket_tx_lock = self.websocket_tx.lock().unwrap();
+            // under the lock so nothing is held back after the flush,
+            // it fits since `send` holds no more than the channel takes
+            for msg in self.pending_sends.lock().unwrap().drain(..) {
+                let _ = websocket_tx.try_send(msg);
+            }
+            *websocket_tx_lock = Some(websocket_tx);
+        }
+
+        // tasks spawned from within the supervisor end up on the same runtime
+        let runtime = self.runtime.clone().unwrap_or_else(Handle::current);
+        let handle = runtime.spawn(async move {
+            let outgoing_rx = Arc::new(Mutex::new(OutgoingQueue::new(outgoing_rx)));
+            let candidates = connect_config.candidate_channels();
+            let mut candidate = 0;
+            // reconnects in a row, since the last connection that got through registration
+            let mut reconnects = 0;
+            let reconnect_jitter = connect_config.reconnect_jitter;
+            // dropped with the supervisor, also when it is aborted
+            let _status = connect_config.status_interval.map(|interval| {
+                AbortOnDrop(spawn_status_emitter(
+                    interval,
+                    proxy_tx.clone(),
+                    events.clone(),
+                    shared.clone(),
+                ))
+            });
+            if let Some(history_file) = connect_config.history.as_ref() {
+                if !shared.history_loaded.load(Ordering::Acquire) {
+                    load_history(&shared, history_file).await;
+                }
+            }
 
-        let handle = tokio::spawn(async move {
             loop {
                 let (shutdown_tx, shutdown_rx) = oneshot::channel();
-                let mut lock = shutdown_mutex.lock().await;
-                lock.replace(shutdown_tx);
-                drop(lock);
+                shutdown_mutex.lock().unwrap().replace(shutdown_tx);
 
-                let connect_config = connect_config.clone();
+                let mut connect_config = connect_config.clone();
+                // a reconnect stays on the channel the last connection switched to
+                connect_config.channel = shared
+                    .switched_channel
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .or_else(|| candidates.get(candidate).cloned());
                 //setup proxy channel for receiving messages from websocket
                 // ttvy_core <-- websocket <-- (twitch server)
-                let (incoming_tx, incoming_rx) = channel::<ChatMessage>(128);
-
-                //setup channel for sending messages over websocket
-                // ttvy_core --> websocket --> (twitch server)
-                let (websocket_tx, outgoing_rx) = channel::<String>(128);
-                let mut controller_websocket_tx = controller_websocket_tx.lock().await;
-                *controller_websocket_tx = Some(websocket_tx);
-                drop(controller_websocket_tx);
-
-                let proxy = spawn_proxy_worker(incoming_rx, &proxy_tx);
-                let _result = tokio::spawn(async move {
-                    connect(connect_config, incoming_tx, outgoing_rx, shutdown_rx).await
+                let (incoming_tx, incoming_rx) = channel::<ChatEvent>(128);
+
+                let proxy = spawn_proxy_worker(
+                    incoming_rx,
+                    &proxy_tx,
+                    &events,
+                    shared.clone(),
+                    delivery.clone(),
+                    dedup.clone(),
+                    connect_config.lossy_delivery,
+                );
+                let connection_shared = shared.clone();
+                let connector = connector.clone();
+                let outgoing_rx = outgoing_rx.clone();
+                let result = tokio::spawn(async move {
+                    connect(
+                        connect_config,
+                        incoming_tx,
+                        outgoing_rx,
+                        shutdown_rx,
+                        connection_shared,
+                        connector,
+                    )
+                    .await
                 })
                 .await;
 
                 proxy.abort();
+                if *shared.connection_state.borrow() == ConnectionState::Connected {
+                    reconnects = 0;
+                }
+                shared
+                    .connection_state
+                    .send_replace(ConnectionState::Disconnected);
+                for state in shared.channels.lock().unwrap().values_mut() {
+                    state.joined = false;
+                }
+
+                let reason = match result {
+                    Ok(reason) => reason,
+                    Err(e) => DisconnectReason::Connection(e.to_string()),
+                };
+                let mut stop = match &reason {
+                    DisconnectReason::Shutdown | DisconnectReason::LoginConflict => true,
+                    DisconnectReason::Connection(_)
+                    | DisconnectReason::Reconnect
+                    | DisconnectReason::HandshakeTimeout => false,
+                    DisconnectReason::Notice { action, .. } => *action != NoticeAction::Retry,
+                };
+                // a channel that can't be joined moves on to the next fallback channel
+                if let DisconnectReason::Notice {
+                    msg_id,
+                    action: NoticeAction::Stop,
+                } = &reason
+                {
+                    if CHANNEL_MSG_IDS.contains(&msg_id.as_str())
+                        && candidate + 1 < candidates.len()
+                    {
+                        shared.switched_channel.lock().unwrap().take();
+                        candidate += 1;
+                        println!("Trying next channel #{}", &candidates[candidate]);
+                        stop = false;
+                    }
+                }
+
+                let event = ChatEvent::Disconnected(reason);
+                let _ = events.send(event.clone());
+                let _ = proxy_tx.send(event).await;
+                if stop {
+                    controller_websocket_tx.lock().unwrap().take();
+                    break;
+                }
+
+                let delay = reconnect_delay(reconnects, reconnect_jitter);
+                reconnects += 1;
+                println!("Reconnecting in {:?}", delay);
+                tokio::time::sleep(delay).await;
             }
         });
 
@@ -136,14 +1008,244 @@ impl Controller {
     }
 }
 
-fn spawn_proxy_worker(mut rx: Receiver<ChatMessage>, tx: &Sender<ChatMessage>) -> JoinHandle<()> {
+/// Wait before the second reconnect in a row, doubled for each one after it
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+///
+/// The wait before reconnect number `attempt`, counted from 0. The first only waits the jitter,
+/// eg after a RECONNECT, the ones after it back off exponentially.
+///
+fn reconnect_delay(attempt: u32, jitter: Duration) -> Duration {
+    let backoff = match attempt {
+        0 => Duration::ZERO,
+        attempt => RECONNECT_BACKOFF
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(MAX_RECONNECT_BACKOFF),
+    };
+    backoff + random_up_to(jitter)
+}
+
+fn random_up_to(max: Duration) -> Duration {
+    // every RandomState is seeded randomly, which is enough for spreading out reconnects
+    let random = RandomState::new().build_hasher().finish();
+    let max = max.as_millis() as u64;
+    Duration::from_millis(random % (max + 1))
+}
+
+///
+/// Puts the saved history before whatever was kept since, up to the history size.
+///
+async fn load_history(shared: &Shared, history_file: &HistoryFile) {
+    let history = history_file.load().await;
+    let mut channels = shared.channels.lock().unwrap();
+    for (channel, messages) in history {
+        let state = channels.entry(channel).or_default();
+        let mut kept = VecDeque::from(messages);
+        kept.append(&mut state.history);
+        let excess = kept.len().saturating_sub(history_file.size);
+        kept.drain(..excess);
+        state.history = kept;
+    }
+    shared.history_loaded.store(true, Ordering::Release);
+}
+
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+///
+/// Sends a `ChatEvent::Status` every `interval`, skipped for a consumer with no room for it
+/// since a late heartbeat is no use.
+///
+fn spawn_status_emitter(
+    interval: Duration,
+    tx: Sender<ChatEvent>,
+    events: broadcast::Sender<ChatEvent>,
+    shared: Arc<Shared>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        // the first tick is immediate, the chat has nothing to report yet
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let event = ChatEvent::Status(shared.status());
+            if events.receiver_count() > 0 {
+                let _ = events.send(event.clone());
+            }
+            let _ = tx.try_send(event);
+        }
+    })
+}
+
+///
+/// While paused, or while events from a pause are still waiting to be taken,
+/// incoming events go to the delivery buffer so they stay in order.
+/// With `lossy` set, events the consumer has no room for are dropped instead of waited on.
+///
+fn spawn_proxy_worker(
+    mut rx: Receiver<ChatEvent>,
+    tx: &Sender<ChatEvent>,
+    events: &broadcast::Sender<ChatEvent>,
+    shared: Arc<Shared>,
+    delivery: Arc<std::sync::Mutex<Delivery>>,
+    dedup: Option<Arc<std::sync::Mutex<Dedup>>>,
+    lossy: bool,
+) -> JoinHandle<()> {
     let tx = tx.clone();
+    let events = events.clone();
 
     tokio::spawn(async move {
         loop {
-            if let Some(msg) = rx.recv().await {
-                let _result = tx.send(msg).await;
+            let flush_at = delivery.lock().unwrap().dropped.flush_at();
+            let msg = tokio::select! {
+                msg = rx.recv() => msg,
+                _ = tokio::time::sleep_until(flush_at.unwrap_or_else(Instant::now)),
+                    if flush_at.is_some() => {
+                    if let Some(dropped) = delivery.lock().unwrap().dropped.flush() {
+                        report_dropped(&tx, dropped);
+                    }
+                    continue;
+                }
+            };
+            // the connection ended, the supervisor aborts this worker next
+            let Some(msg) = msg else {
+                break;
+            };
+            if let Some(dedup) = dedup.as_ref() {
+                if !dedup.lock().unwrap().check(&msg) {
+                    continue;
+                }
+            }
+
+            if events.receiver_count() > 0 {
+                let _ = events.send(msg.clone());
+            }
+            if let ChatEvent::Message(message) = &msg {
+                if let Some(channel) = message.channel.as_ref() {
+                    let mut latest = shared.latest.lock().unwrap();
+                    latest
+                        .entry(channel.clone())
+                        .or_insert_with(|| watch::channel(None).0)
+                        .send_replace(Some(message.clone()));
+                }
+            }
+
+            let mut warning = None;
+            let msg = {
+                let mut delivery = delivery.lock().unwrap();
+                if delivery.paused || !delivery.buffer.is_empty() {
+                    if delivery.buffer.len() == PAUSE_BUFFER_CAPACITY {
+                        delivery.buffer.pop_front();
+                        warning = delivery.dropped.record();
+                    }
+                    delivery.buffer.push_back(msg);
+                    None
+                } else {
+                    Some(msg)
+                }
+            };
+
+            if let Some(msg) = msg {
+                if !lossy {
+                    let _result = tx.send(msg).await;
+                } else if let Err(TrySendError::Full(_)) = tx.try_send(msg) {
+                    warning = delivery.lock().unwrap().dropped.record();
+                }
+            }
+
+            if let Some(dropped) = warning {
+                report_dropped(&tx, dropped);
             }
         }
     })
 }
+
+fn report_dropped(tx: &Sender<ChatEvent>, dropped: DroppedEvents) {
+    if dropped.window.is_zero() {
+        eprintln!("Dropped {} events (consumer too slow)", dropped.count);
+    } else {
+        eprintln!(
+            "Dropped {} events in the last {:.1}s (consumer too slow)",
+            dropped.count,
+            dropped.window.as_secs_f64()
+        );
+    }
+    // best effort, the consumer is behind by definition
+    let _ = tx.try_send(ChatEvent::Dropped(dropped));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn drop_warnings_are_spaced_out_and_the_last_burst_is_reported() {
+        let mut dropped = DropCounter::default();
+        let start = Instant::now();
+        let first = DroppedEvents {
+            count: 1,
+            window: Duration::ZERO,
+        };
+        assert_eq!(dropped.record(), Some(first));
+        // the burst is reported over the time since its first drop, not since the warning
+        tokio::time::advance(Duration::from_secs(3)).await;
+        for _ in 0..4 {
+            assert_eq!(dropped.record(), None);
+        }
+
+        assert_eq!(dropped.flush_at(), Some(start + DROP_WARNING_INTERVAL));
+        tokio::time::advance(Duration::from_secs(7) - Duration::from_millis(1)).await;
+        assert_eq!(dropped.flush(), None);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        let burst = DroppedEvents {
+            count: 4,
+            window: Duration::from_secs(7),
+        };
+        assert_eq!(dropped.flush(), Some(burst));
+        assert_eq!(dropped.flush_at(), None);
+
+        // a quiet stretch isn't part of the next window
+        tokio::time::advance(DROP_WARNING_INTERVAL * 2).await;
+        let late = DroppedEvents {
+            count: 1,
+            window: Duration::ZERO,
+        };
+        assert_eq!(dropped.record(), Some(late));
+    }
+
+    #[test]
+    fn the_dry_run_log_keeps_the_latest_lines() {
+        let shared = Shared::default();
+        for i in 0..DRY_RUN_LOG_CAPACITY + 2 {
+            shared.log_dry_run(i.to_string());
+        }
+        let log: Vec<String> = std::mem::take(&mut *shared.dry_run_log.lock().unwrap()).into();
+        assert_eq!(log.len(), DRY_RUN_LOG_CAPACITY);
+        assert_eq!(log.first().map(String::as_str), Some("2"));
+        assert_eq!(log.last(), Some(&(DRY_RUN_LOG_CAPACITY + 1).to_string()));
+    }
+
+    #[test]
+    fn reconnects_back_off_exponentially_up_to_the_cap() {
+        let delays: Vec<u64> = (0..9)
+            .map(|attempt| reconnect_delay(attempt, Duration::ZERO).as_secs())
+            .collect();
+        assert_eq!(delays, [0, 1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(
+            reconnect_delay(u32::MAX, Duration::ZERO),
+            MAX_RECONNECT_BACKOFF
+        );
+
+        let jitter = Duration::from_millis(500);
+        for _ in 0..100 {
+            let delay = reconnect_delay(2, jitter);
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(2) + jitter);
+        }
+    }
+}
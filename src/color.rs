@@ -0,0 +1,168 @@
+use std::fmt;
+use std::str::FromStr;
+
+///
+/// A chat color, either one of the colors every user can pick or a hex color,
+/// which `/color` only accepts from Turbo and Prime users.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Color {
+    Named(NamedColor),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        match self {
+            Color::Named(named) => named.rgb(),
+            Color::Rgb(r, g, b) => (*r, *g, *b),
+        }
+    }
+
+    ///
+    /// The color as `#RRGGBB`, like the `color` tag of a message.
+    ///
+    pub fn hex(&self) -> String {
+        let (r, g, b) = self.rgb();
+        format!("#{:02X}{:02X}{:02X}", r, g, b)
+    }
+}
+
+///
+/// Names are written as Twitch lists them, eg `SpringGreen`, hex colors as `#RRGGBB`.
+/// Both are what `/color` takes.
+///
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Color::Named(named) => write!(f, "{}", named.name()),
+            Color::Rgb(..) => write!(f, "{}", self.hex()),
+        }
+    }
+}
+
+///
+/// Parses `#RRGGBB` or a Twitch color name, ignoring case.
+///
+impl FromStr for Color {
+    type Err = InvalidColor;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(hex) = s.strip_prefix('#') {
+            let channel = |range: std::ops::Range<usize>| {
+                hex.get(range)
+                    .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+            };
+            return match (hex.len(), channel(0..2), channel(2..4), channel(4..6)) {
+                (6, Some(r), Some(g), Some(b)) => Ok(Color::Rgb(r, g, b)),
+                _ => Err(InvalidColor(s.to_string())),
+            };
+        }
+
+        NamedColor::ALL
+            .iter()
+            .find(|named| named.name().eq_ignore_ascii_case(s))
+            .map(|named| Color::Named(*named))
+            .ok_or_else(|| InvalidColor(s.to_string()))
+    }
+}
+
+///
+/// A color that is neither `#RRGGBB` nor one of `NamedColor::ALL`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidColor(pub String);
+
+impl fmt::Display for InvalidColor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid color {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidColor {}
+
+///
+/// The colors `/color` accepts by name from every user.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NamedColor {
+    Blue,
+    BlueViolet,
+    CadetBlue,
+    Chocolate,
+    Coral,
+    DodgerBlue,
+    Firebrick,
+    GoldenRod,
+    Green,
+    HotPink,
+    OrangeRed,
+    Red,
+    SeaGreen,
+    SpringGreen,
+    YellowGreen,
+}
+
+impl NamedColor {
+    pub const ALL: [NamedColor; 15] = [
+        NamedColor::Blue,
+        NamedColor::BlueViolet,
+        NamedColor::CadetBlue,
+        NamedColor::Chocolate,
+        NamedColor::Coral,
+        NamedColor::DodgerBlue,
+        NamedColor::Firebrick,
+        NamedColor::GoldenRod,
+        NamedColor::Green,
+        NamedColor::HotPink,
+        NamedColor::OrangeRed,
+        NamedColor::Red,
+        NamedColor::SeaGreen,
+        NamedColor::SpringGreen,
+        NamedColor::YellowGreen,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NamedColor::Blue => "Blue",
+            NamedColor::BlueViolet => "BlueViolet",
+            NamedColor::CadetBlue => "CadetBlue",
+            NamedColor::Chocolate => "Chocolate",
+            NamedColor::Coral => "Coral",
+            NamedColor::DodgerBlue => "DodgerBlue",
+            NamedColor::Firebrick => "Firebrick",
+            NamedColor::GoldenRod => "GoldenRod",
+            NamedColor::Green => "Green",
+            NamedColor::HotPink => "HotPink",
+            NamedColor::OrangeRed => "OrangeRed",
+            NamedColor::Red => "Red",
+            NamedColor::SeaGreen => "SeaGreen",
+            NamedColor::SpringGreen => "SpringGreen",
+            NamedColor::YellowGreen => "YellowGreen",
+        }
+    }
+
+    ///
+    /// The hex color Twitch shows the name as.
+    ///
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        match self {
+            NamedColor::Blue => (0x00, 0x00, 0xFF),
+            NamedColor::BlueViolet => (0x8A, 0x2B, 0xE2),
+            NamedColor::CadetBlue => (0x5F, 0x9E, 0xA0),
+            NamedColor::Chocolate => (0xD2, 0x69, 0x1E),
+            NamedColor::Coral => (0xFF, 0x7F, 0x50),
+            NamedColor::DodgerBlue => (0x1E, 0x90, 0xFF),
+            NamedColor::Firebrick => (0xB2, 0x22, 0x22),
+            NamedColor::GoldenRod => (0xDA, 0xA5, 0x20),
+            NamedColor::Green => (0x00, 0x80, 0x00),
+            NamedColor::HotPink => (0xFF, 0x69, 0xB4),
+            NamedColor::OrangeRed => (0xFF, 0x45, 0x00),
+            NamedColor::Red => (0xFF, 0x00, 0x00),
+            NamedColor::SeaGreen => (0x2E, 0x8B, 0x57),
+            NamedColor::SpringGreen => (0x00, 0xFF, 0x7F),
+            NamedColor::YellowGreen => (0x9A, 0xCD, 0x32),
+        }
+    }
+}
@@ -1,56 +1,382 @@
-use std::{env, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::policy::NoticePolicy;
 
 type TTVChannel = String;
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+
+///
+/// How a message identical to the previous one is altered so Twitch doesn't drop it.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AntiDupeMode {
+    /// Send duplicates unchanged.
+    Off,
+    /// Toggle a ` <char>` suffix on consecutive identical messages.
+    Char(char),
+}
+
+impl Default for AntiDupeMode {
+    fn default() -> Self {
+        AntiDupeMode::Char('\u{E0000}')
+    }
+}
+
+///
+/// What `Chat::send` does with a message while no channel is joined, eg before `join`,
+/// after `leave` or once the supervisor stopped. While reconnecting messages always wait
+/// in the outgoing queue.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SendBehavior {
+    /// Drop the message and return `Ok`.
+    Drop,
+    /// Fail with `SendError::NotConnected`.
+    #[default]
+    Error,
+    /// Hold up to this many messages and send them on the next `join`,
+    /// failing with `SendError::QueueFull` beyond that.
+    Queue(usize),
+}
+
+///
+/// The capabilities requested with `CAP REQ` on connect.
+/// `tags` carries the metadata most events are parsed from, `commands` adds eg NOTICE,
+/// CLEARCHAT, USERNOTICE and RECONNECT, and `membership` adds JOIN/PART of other users.
+/// Only `tags` is requested by default, the events `commands` adds have to be turned on.
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct CapabilitySet {
+    pub tags: bool,
+    pub commands: bool,
+    pub membership: bool,
+}
+
+impl Default for CapabilitySet {
+    fn default() -> Self {
+        Self {
+            tags: true,
+            commands: false,
+            membership: false,
+        }
+    }
+}
+
+impl CapabilitySet {
+    pub fn is_empty(&self) -> bool {
+        !(self.tags || self.commands || self.membership)
+    }
+
+    ///
+    /// The `CAP REQ` line, `None` when nothing is requested.
+    ///
+    pub fn request_line(&self) -> Option<String> {
+        let capabilities: Vec<&str> = [
+            (self.tags, "twitch.tv/tags"),
+            (self.commands, "twitch.tv/commands"),
+            (self.membership, "twitch.tv/membership"),
+        ]
+        .into_iter()
+        .filter_map(|(requested, capability)| requested.then_some(capability))
+        .collect();
+
+        (!capabilities.is_empty()).then(|| format!("CAP REQ :{}", capabilities.join(" ")))
+    }
+}
+
+pub const DEFAULT_ENDPOINT: &str = "ws://irc-ws.chat.twitch.tv:80";
+pub const DEFAULT_SEND_SPACING_MS: u64 = 250;
+pub const DEFAULT_HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+pub const DEFAULT_RECONNECT_JITTER_MS: u64 = 1000;
+
+///
+/// Every field has a default and unknown fields are kept in `extra`, so a `state.json`
+/// written by an older or newer version still loads, and saving doesn't lose anything.
+///
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
 pub struct Config {
     pub channel: Option<TTVChannel>,
+    /// Channels to fall back to, in order, when `channel` can't be joined.
+    pub channels: Vec<TTVChannel>,
     pub oauth: Option<String>,
     pub nick: Option<String>,
+    /// Drop messages whose `id` was seen within the last `dedup` messages.
+    pub dedup: Option<usize>,
+    /// Collect outgoing messages instead of sending them, see `Chat::take_dry_run_messages`.
+    pub dry_run: bool,
+    pub notice_policy: NoticePolicy,
+    /// Websocket URL to connect to, defaults to `DEFAULT_ENDPOINT`.
+    pub endpoint: Option<String>,
+    /// Minimum time between two outgoing messages, defaults to `DEFAULT_SEND_SPACING_MS`.
+    pub send_spacing_ms: Option<u64>,
+    pub antidupe: AntiDupeMode,
+    pub send_behavior: SendBehavior,
+    /// Drop incoming events instead of waiting while the consumer is behind,
+    /// see `ChatEvent::Dropped`.
+    pub lossy_delivery: bool,
+    /// How long to wait for the server to acknowledge the capabilities before reconnecting,
+    /// defaults to `DEFAULT_HANDSHAKE_TIMEOUT_MS`.
+    pub handshake_timeout_ms: Option<u64>,
+    /// Up to how much random time is added to each reconnect's backoff, so clients dropped
+    /// by the same outage don't reconnect at once. Defaults to `DEFAULT_RECONNECT_JITTER_MS`.
+    pub reconnect_jitter_ms: Option<u64>,
+    /// Feed every received line to `Chat::raw_events`, off by default to not copy each line.
+    pub raw_events: bool,
+    /// Feed every line sent to the server to `Chat::sent_lines`, off by default like `raw_events`.
+    pub raw_outgoing: bool,
+    /// Trim trailing whitespace, and a trailing `\u{E0000}` anti-dupe character,
+    /// off received messages. Off by default to keep messages exactly as sent.
+    pub trim_trailing_whitespace: bool,
+    pub capabilities: CapabilitySet,
+    /// Command prefixes of other bots in the channel, eg `["!", "?"]`. Messages starting with one
+    /// are dropped before delivery, see `ChatMessage::has_prefix`. Empty by default.
+    pub ignored_prefixes: Vec<String>,
+    /// How long `fetch_auth_token` waits for the browser flow to finish, forever if unset.
+    pub auth_timeout_ms: Option<u64>,
+    /// Send a `ChatEvent::Status` this often, for dashboards. Off if unset.
+    pub status_interval_ms: Option<u64>,
+    /// Keep the recent messages of each channel with their deletions and bans,
+    /// see `Chat::timeline`. Off by default to not copy each message.
+    pub mod_view: bool,
+    /// Rejoin the channel after an unexpected PART, eg a kick, up to this many times
+    /// per connection, waiting twice as long each time. Off if unset.
+    pub auto_rejoin: Option<u32>,
+    /// Keep the last this many messages of each channel, see `Chat::history`, saved on `leave`
+    /// and `close` and loaded again on the first `join`. Off if unset or 0.
+    pub history_size: Option<usize>,
+    /// Where the history is saved, apart from the config so it never holds credentials.
+    /// Defaults to `~/.ttvy_core/history.txt`.
+    pub history_path: Option<String>,
+    /// Fields this version doesn't know about, written back as they were.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+///
+/// Masks a token for `Debug` output.
+///
+pub(crate) fn redact_oauth(oauth: &Option<String>) -> Option<&'static str> {
+    oauth.as_ref().map(|_| "***")
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("channel", &self.channel)
+            .field("channels", &self.channels)
+            .field("oauth", &redact_oauth(&self.oauth))
+            .field("nick", &self.nick)
+            .field("dedup", &self.dedup)
+            .field("dry_run", &self.dry_run)
+            .field("notice_policy", &self.notice_policy)
+            .field("endpoint", &self.endpoint)
+            .field("send_spacing_ms", &self.send_spacing_ms)
+            .field("antidupe", &self.antidupe)
+            .field("send_behavior", &self.send_behavior)
+            .field("lossy_delivery", &self.lossy_delivery)
+            .field("handshake_timeout_ms", &self.handshake_timeout_ms)
+            .field("reconnect_jitter_ms", &self.reconnect_jitter_ms)
+            .field("raw_events", &self.raw_events)
+            .field("raw_outgoing", &self.raw_outgoing)
+            .field("trim_trailing_whitespace", &self.trim_trailing_whitespace)
+            .field("capabilities", &self.capabilities)
+            .field("ignored_prefixes", &self.ignored_prefixes)
+            .field("auth_timeout_ms", &self.auth_timeout_ms)
+            .field("status_interval_ms", &self.status_interval_ms)
+            .field("mod_view", &self.mod_view)
+            .field("auto_rejoin", &self.auto_rejoin)
+            .field("history_size", &self.history_size)
+            .field("history_path", &self.history_path)
+            .field("extra", &self.extra)
+            .finish()
+    }
 }
 
 impl Config {
     pub async fn new() -> Self {
-        let save_dir = Self::get_save_dir();
+        let save_dir = match Self::get_save_dir() {
+            Ok(save_dir) => save_dir,
+            Err(e) => {
+                eprintln!("Config directory unusable, using the default config: {}", e);
+                return Self::default();
+            }
+        };
 
         match fs::read_to_string(&save_dir).await {
             Ok(c) => serde_json::from_str(&c).expect("Bad config"),
-            Err(_) => Self {
-                channel: None,
-                oauth: None,
-                nick: None,
-            },
+            Err(_) => {
+                Self::warn_unusable_save_dir().await;
+                Self::default()
+            }
+        }
+    }
+
+    ///
+    /// Checks that `HOME` is a directory and `~/.ttvy_core` is one or can be created in it,
+    /// so a config that can't be read or saved there gets an explanation.
+    /// Returns the directory, which this doesn't create.
+    ///
+    pub async fn check_save_dir() -> Result<PathBuf, tokio::io::Error> {
+        let home = Self::home()?;
+        let unusable = |path: &str, reason: String| {
+            tokio::io::Error::new(
+                tokio::io::ErrorKind::NotFound,
+                format!("{} {}", path, reason),
+            )
+        };
+
+        match fs::metadata(&home).await {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => return Err(unusable(&home, "(HOME) is not a directory".to_string())),
+            Err(e) => return Err(unusable(&home, format!("(HOME) can't be accessed: {}", e))),
+        }
+
+        let save_dir = Self::get_save_dir()?;
+        let save_dir = save_dir.parent().unwrap_or(&save_dir).to_path_buf();
+        match fs::metadata(&save_dir).await {
+            Ok(metadata) if metadata.is_dir() => Ok(save_dir),
+            Ok(_) => Err(unusable(
+                &save_dir.display().to_string(),
+                "is not a directory".to_string(),
+            )),
+            Err(e) if e.kind() == tokio::io::ErrorKind::NotFound => Ok(save_dir),
+            Err(e) => Err(unusable(
+                &save_dir.display().to_string(),
+                format!("can't be accessed: {}", e),
+            )),
+        }
+    }
+
+    ///
+    /// Said when no config could be read, before falling back to the defaults.
+    ///
+    pub(crate) async fn warn_unusable_save_dir() {
+        if let Err(e) = Self::check_save_dir().await {
+            eprintln!("Config directory unusable, using the default config: {}", e);
         }
     }
 
-    fn get_save_dir() -> PathBuf {
-        let mut save_dir = env::var("HOME").expect("Failed to get HOME");
+    fn home() -> Result<String, tokio::io::Error> {
+        env::var("HOME")
+            .ok()
+            .filter(|home| !home.is_empty())
+            .ok_or_else(|| {
+                tokio::io::Error::new(
+                    tokio::io::ErrorKind::NotFound,
+                    "HOME is not set, so there is no ~/.ttvy_core",
+                )
+            })
+    }
+
+    fn get_save_dir() -> Result<PathBuf, tokio::io::Error> {
+        let mut save_dir = Self::home()?;
         save_dir.push_str("/.ttvy_core/state.json");
-        PathBuf::from_str(&save_dir).unwrap()
+        Ok(PathBuf::from(save_dir))
+    }
+
+    ///
+    /// `history_path`, or `history.txt` next to the default config.
+    ///
+    pub fn history_file(&self) -> Result<PathBuf, tokio::io::Error> {
+        match self.history_path.as_ref() {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => Ok(Self::get_save_dir()?.with_file_name("history.txt")),
+        }
     }
 
     pub async fn load() -> Result<Self, tokio::io::Error> {
-        let save_dir = Self::get_save_dir();
+        FileStore::try_default()?.load().await
+    }
 
-        match fs::read_to_string(&save_dir).await {
-            Ok(c) => Ok(serde_json::from_str(&c).expect("Bad config")),
-            Err(e) => Err(e),
-        }
+    ///
+    /// Reads a JSON config from `reader`, eg an embedded resource or a network stream.
+    ///
+    pub async fn from_reader(mut reader: impl AsyncRead + Unpin) -> Result<Self, tokio::io::Error> {
+        let mut c = String::new();
+        reader.read_to_string(&mut c).await?;
+        c.parse()
     }
 
-    pub async fn save(&self) {
-        let data = serde_json::json!(self).to_string();
+    pub async fn load_from(store: &impl ConfigStore) -> Result<Self, tokio::io::Error> {
+        store.load().await
+    }
 
-        let save_dir = Self::get_save_dir();
-        let _ = tokio::fs::create_dir_all(save_dir.parent().unwrap()).await;
-        match tokio::fs::write(&save_dir, data).await {
+    pub async fn save_to(&self, store: &impl ConfigStore) -> Result<(), tokio::io::Error> {
+        store.save(self).await
+    }
+
+    ///
+    /// Sets `channel` here and in the saved config, leaving the other saved fields as they are
+    /// even if this config is stale.
+    ///
+    pub async fn update_channel(&mut self, channel: &str) -> Result<(), tokio::io::Error> {
+        let channel = channel.to_string();
+        self.channel = Some(channel.clone());
+        Self::update_in(&FileStore::try_default()?, |config| {
+            config.channel = Some(channel)
+        })
+        .await
+    }
+
+    ///
+    /// Like `update_channel`, for `oauth`.
+    ///
+    pub async fn update_oauth(&mut self, oauth: &str) -> Result<(), tokio::io::Error> {
+        let oauth = oauth.to_string();
+        self.oauth = Some(oauth.clone());
+        Self::update_in(&FileStore::try_default()?, |config| {
+            config.oauth = Some(oauth)
+        })
+        .await
+    }
+
+    ///
+    /// Loads the config of `store`, or the default one if there is none yet, applies `update`
+    /// and saves it. Updates within this process run one at a time, so none is lost.
+    ///
+    pub async fn update_in(
+        store: &impl ConfigStore,
+        update: impl FnOnce(&mut Config),
+    ) -> Result<(), tokio::io::Error> {
+        static UPDATE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+        let _lock = UPDATE.lock().await;
+
+        let mut config = match store.load().await {
+            Ok(config) => config,
+            Err(e) if e.kind() == tokio::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(e),
+        };
+        update(&mut config);
+        store.save(&config).await
+    }
+
+    pub async fn save(&self) {
+        let saved = match FileStore::try_default() {
+            Ok(store) => store.save(self).await,
+            Err(e) => Err(e),
+        };
+        match saved {
             Ok(_) => println!("Saved config (~/.ttvy_core/state.json)"),
-            Err(_) => eprintln!("Failed to save config (~/.ttvy_core/state.json)"),
+            Err(e) => eprintln!("Failed to save config (~/.ttvy_core/state.json): {}", e),
         }
     }
 
+    pub fn endpoint(&self) -> &str {
+        self.endpoint.as_deref().unwrap_or(DEFAULT_ENDPOINT)
+    }
+
     pub fn set_initial_channel(&mut self) {
         let args: Vec<String> = env::args().collect();
 
@@ -59,27 +385,321 @@ impl Config {
         }
     }
 
+    ///
+    /// A copy of the config that is safe to log, with the oauth token masked.
+    ///
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if config.oauth.is_some() {
+            config.oauth = Some("***".to_string());
+        }
+        config
+    }
+
     pub async fn fetch_auth_token(&mut self) -> &mut Self {
-        let token = http::get_ttv_token().await;
+        if let Err(e) = self.try_fetch_auth_token().await {
+            eprintln!("Failed to get an authtoken: {}", e);
+        }
+        self
+    }
+
+    ///
+    /// Like `fetch_auth_token`, but fails with `TimedOut` once `auth_timeout_ms` has passed
+    /// without a token, eg because the browser tab was closed. The webserver is shut down either way.
+    ///
+    pub async fn try_fetch_auth_token(&mut self) -> Result<&mut Self, tokio::io::Error> {
+        let token = http::get_ttv_token(self.auth_timeout()).await?;
         let _ = self.oauth.insert(token);
         println!("Authtoken has been set!");
+        Ok(self)
+    }
+
+    ///
+    /// Like `fetch_auth_token`, but reuses an already running `AuthServer`.
+    ///
+    pub async fn fetch_auth_token_with(&mut self, server: &mut AuthServer) -> &mut Self {
+        match server.token_timeout(self.auth_timeout()).await {
+            Ok(token) => {
+                let _ = self.oauth.insert(token);
+                println!("Authtoken has been set!");
+            }
+            Err(e) => eprintln!("Failed to get an authtoken: {}", e),
+        }
         self
     }
+
+    fn auth_timeout(&self) -> Option<Duration> {
+        self.auth_timeout_ms.map(Duration::from_millis)
+    }
+
+    ///
+    /// Sets `oauth` from the token the official `twitch` CLI stored with `twitch token -u`,
+    /// read from the first of `cli_token_paths` that exists.
+    /// Fails with `NotFound` if there is none,
+    /// or `InvalidData` if the token is malformed, expired or lacks the `chat:read` scope.
+    ///
+    /// The expiry and scopes the CLI wrote down are checked first, then the token is sent to
+    /// Twitch's validate endpoint so a revoked one fails here instead of at login.
+    /// Fails with `PermissionDenied` if Twitch rejects it.
+    ///
+    pub async fn import_cli_token(&mut self) -> Result<&mut Self, tokio::io::Error> {
+        for path in Self::cli_token_paths() {
+            let Ok(contents) = fs::read_to_string(&path).await else {
+                continue;
+            };
+            let token = unexpired_cli_token(&contents).map_err(|e| {
+                tokio::io::Error::new(
+                    tokio::io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e),
+                )
+            })?;
+            http::validate_token(&token).await?;
+            let _ = self.oauth.insert(token);
+            println!("Authtoken has been imported from {}", path.display());
+            return Ok(self);
+        }
+        Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::NotFound,
+            "No twitch CLI credentials found",
+        ))
+    }
+
+    ///
+    /// Where the `twitch` CLI keeps its `.twitch-cli.env`, in the order they are read:
+    /// `$XDG_CONFIG_HOME/twitch-cli/`, `~/.config/twitch-cli/`,
+    /// `~/Library/Application Support/twitch-cli/` and `%APPDATA%\twitch-cli\`.
+    ///
+    pub fn cli_token_paths() -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = vec![];
+        if let Ok(config) = env::var("XDG_CONFIG_HOME") {
+            dirs.push(PathBuf::from(config));
+        }
+        if let Ok(home) = env::var("HOME") {
+            dirs.push(PathBuf::from(&home).join(".config"));
+            dirs.push(PathBuf::from(&home).join("Library/Application Support"));
+        }
+        if let Ok(appdata) = env::var("APPDATA") {
+            dirs.push(PathBuf::from(appdata));
+        }
+        dirs.into_iter()
+            .map(|dir| dir.join("twitch-cli").join(".twitch-cli.env"))
+            .collect()
+    }
 }
 
+///
+/// The `ACCESSTOKEN` of a `.twitch-cli.env`, whose lines are `KEY=value`, unless the recorded
+/// `TOKENEXPIRATION` passed or the recorded `TOKENSCOPES` lack `chat:read`.
+///
+fn unexpired_cli_token(contents: &str) -> Result<String, &'static str> {
+    let values: HashMap<&str, &str> = contents
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim().trim_matches('"')))
+        .collect();
+
+    let token = values
+        .get("ACCESSTOKEN")
+        .map(|token| token.trim_start_matches("oauth:"))
+        .filter(|token| !token.is_empty())
+        .ok_or("no ACCESSTOKEN")?;
+    if !token.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err("malformed ACCESSTOKEN");
+    }
+    // app access tokens have no scopes and can't log in to chat
+    if let Some(scopes) = values.get("TOKENSCOPES") {
+        if !scopes.contains("chat:read") {
+            return Err("token lacks the chat:read scope");
+        }
+    }
+    if let Some(expiration) = values.get("TOKENEXPIRATION").and_then(|e| unix_time(e)) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as i64);
+        if expiration <= now {
+            return Err("token expired");
+        }
+    }
+    Ok(token.to_string())
+}
+
+///
+/// Seconds since the epoch of an RFC 3339 time like `2024-03-01T12:00:00.5+01:00`.
+///
+fn unix_time(time: &str) -> Option<i64> {
+    let number = |time: &str, range: std::ops::Range<usize>| {
+        let digits = time.get(range)?;
+        let digits = Some(digits).filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))?;
+        digits.parse::<i64>().ok()
+    };
+    let separated = [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')]
+        .iter()
+        .all(|(at, separator)| time.as_bytes().get(*at) == Some(separator));
+    if !separated || !matches!(time.as_bytes().get(10), Some(b'T' | b't' | b' ')) {
+        return None;
+    }
+    let (year, month, day) = (
+        number(time, 0..4)?,
+        number(time, 5..7)?,
+        number(time, 8..10)?,
+    );
+    let (hour, minute, second) = (
+        number(time, 11..13)?,
+        number(time, 14..16)?,
+        number(time, 17..19)?,
+    );
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    // a leap second counts as the last second of its minute
+    let second = match second {
+        0..=59 => second,
+        60 => 59,
+        _ => return None,
+    };
+
+    let zone = time.get(19..)?;
+    let zone = match zone.strip_prefix('.') {
+        Some(fraction) => {
+            let zone = fraction.trim_start_matches(|c: char| c.is_ascii_digit());
+            if zone.len() == fraction.len() {
+                return None;
+            }
+            zone
+        }
+        None => zone,
+    };
+    let offset = match zone {
+        "Z" | "z" => 0,
+        zone if zone.len() == 6 && zone.as_bytes()[3] == b':' => {
+            let sign = match zone.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let (hours, minutes) = (number(zone, 1..3)?, number(zone, 4..6)?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            sign * (hours * 3600 + minutes * 60)
+        }
+        _ => return None,
+    };
+
+    // days since the epoch of a proleptic Gregorian date
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+///
+/// Parses a JSON config, failing with `InvalidData` like the stores.
+///
+impl FromStr for Config {
+    type Err = tokio::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+            .map_err(|e| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, e))
+    }
+}
+
+///
+/// Where a `Config` is loaded from and saved to.
+/// `FileStore` is the default, other stores can keep credentials in eg a keyring or a vault.
+///
+pub trait ConfigStore {
+    fn load(&self) -> impl Future<Output = Result<Config, tokio::io::Error>> + Send;
+    fn save(&self, config: &Config) -> impl Future<Output = Result<(), tokio::io::Error>> + Send;
+}
+
+///
+/// Stores the config as JSON, by default at `~/.ttvy_core/state.json`.
+///
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    ///
+    /// The store at `~/.ttvy_core/state.json`, fails if `HOME` isn't set.
+    ///
+    pub fn try_default() -> Result<Self, tokio::io::Error> {
+        Config::get_save_dir().map(Self::new)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+impl ConfigStore for FileStore {
+    async fn load(&self) -> Result<Config, tokio::io::Error> {
+        let c = fs::read_to_string(&self.path).await?;
+        c.parse()
+    }
+
+    async fn save(&self, config: &Config) -> Result<(), tokio::io::Error> {
+        write_atomic(&self.path, serde_json::json!(config).to_string()).await
+    }
+}
+
+///
+/// Writes to a temporary file next to `path` first, so a crash mid-write
+/// doesn't leave a truncated file behind. Creates the missing directories of `path`.
+///
+pub(crate) async fn write_atomic(
+    path: &Path,
+    data: impl AsRef<[u8]>,
+) -> Result<(), tokio::io::Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await.map_err(|e| {
+            tokio::io::Error::new(
+                e.kind(),
+                format!("can't create {}: {}", parent.display(), e),
+            )
+        })?;
+    }
+    let mut temp = path.to_path_buf().into_os_string();
+    temp.push(".tmp");
+    fs::write(&temp, data).await?;
+    fs::rename(&temp, path).await
+}
+
+pub use http::AuthServer;
+
 mod http {
-    use std::{process::Stdio, sync::Arc};
+    use std::{process::Stdio, sync::Arc, time::Duration};
 
     use axum::{
-        http::{header, HeaderValue},
+        http::{header, HeaderValue, StatusCode},
         response::IntoResponse,
         routing::{get, post},
         Extension, Json, Router,
     };
 
     use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
         process::Command,
-        sync::mpsc::{channel, Receiver, Sender},
+        sync::{
+            mpsc::{channel, error::TrySendError, Receiver, Sender},
+            oneshot,
+        },
         task::JoinHandle,
     };
 
@@ -87,6 +707,11 @@ mod http {
 
     use serde::{Deserialize, Serialize};
 
+    use tokio_rustls::{
+        rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+
     #[derive(Serialize, Deserialize, Debug)]
     struct TokenBody {
         pub token: String,
@@ -96,28 +721,178 @@ mod http {
     #[folder = "static/"]
     struct Asset;
 
-    pub async fn get_ttv_token() -> String {
-        let api_url: String = "https://id.twitch.tv/oauth2/authorize?\
-            response_type=token\
-            &client_id=m0y30jcckwn2a7m7hh0djrg47wvbuk\
-            &scope=chat%3Aread%20chat%3Aedit\
-            &redirect_uri=http://localhost:4537"
-            .to_string();
+    pub async fn get_ttv_token(timeout: Option<Duration>) -> Result<String, std::io::Error> {
+        let mut server = AuthServer::start().await?;
+        let msg = server.token_timeout(timeout).await;
+        server.shutdown().await;
+        msg
+    }
+
+    ///
+    /// A running auth webserver. Keeping it around lets a long-running app re-auth
+    /// through the same listener instead of binding the port again.
+    /// The listener is shut down on `shutdown` or when the server is dropped.
+    ///
+    #[derive(Debug)]
+    pub struct AuthServer {
+        token_rx: Receiver<String>,
+        shutdown_tx: Option<oneshot::Sender<()>>,
+        handle: Option<JoinHandle<()>>,
+    }
 
-        println!("Complete authentication at\n{}", &api_url);
-        if open_browser(&api_url).await.is_err() {
-            println!("Failed to open browser automatically, please navigate manually.")
+    impl AuthServer {
+        pub async fn start() -> Result<Self, std::io::Error> {
+            let (token_tx, token_rx) = channel::<String>(1);
+            let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+            let listener = tokio::net::TcpListener::bind("0.0.0.0:4537").await?;
+            let handle = start_webserver(listener, token_tx, shutdown_rx);
+
+            Ok(Self {
+                token_rx,
+                shutdown_tx: Some(shutdown_tx),
+                handle: Some(handle),
+            })
         }
 
-        println!("Waiting for token...");
-        let (token_tx, mut token_rx) = channel::<String>(1);
-        let (shutdown_tx, shutdown_rx) = channel::<()>(1);
+        ///
+        /// Runs one authentication flow, returning `None` if the webserver has stopped.
+        /// A token posted outside of a flow, eg by reloading the page, is discarded.
+        ///
+        pub async fn token(&mut self) -> Option<String> {
+            while self.token_rx.try_recv().is_ok() {}
 
-        let _handle = start_webserver(token_tx, shutdown_rx);
+            let api_url: String = "https://id.twitch.tv/oauth2/authorize?\
+                response_type=token\
+                &client_id=m0y30jcckwn2a7m7hh0djrg47wvbuk\
+                &scope=chat%3Aread%20chat%3Aedit\
+                &redirect_uri=http://localhost:4537"
+                .to_string();
 
-        let msg = token_rx.recv().await.unwrap();
-        shutdown_tx.send(()).await.unwrap();
-        msg
+            println!("Complete authentication at\n{}", &api_url);
+            if open_browser(&api_url).await.is_err() {
+                println!("Failed to open browser automatically, please navigate manually.")
+            }
+
+            println!("Waiting for token...");
+            self.token_rx.recv().await
+        }
+
+        ///
+        /// Like `token`, but fails with `TimedOut` if no token arrived within `timeout`.
+        ///
+        pub async fn token_timeout(
+            &mut self,
+            timeout: Option<Duration>,
+        ) -> Result<String, std::io::Error> {
+            let token = match timeout {
+                Some(timeout) => {
+                    tokio::time::timeout(timeout, self.token())
+                        .await
+                        .map_err(|_| {
+                            std::io::Error::new(
+                                std::io::ErrorKind::TimedOut,
+                                "No token received before the auth timeout",
+                            )
+                        })?
+                }
+                None => self.token().await,
+            };
+            token.ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "Auth server stopped before a token was received",
+                )
+            })
+        }
+
+        pub async fn shutdown(mut self) {
+            if let Some(shutdown_tx) = self.shutdown_tx.take() {
+                let _ = shutdown_tx.send(());
+            }
+            if let Some(handle) = self.handle.take() {
+                let _ = handle.await;
+            }
+        }
+    }
+
+    impl Drop for AuthServer {
+        fn drop(&mut self) {
+            if let Some(shutdown_tx) = self.shutdown_tx.take() {
+                let _ = shutdown_tx.send(());
+            }
+        }
+    }
+
+    ///
+    /// Asks Twitch whether `token` is still valid, with a plain HTTP/1.0 request
+    /// so the connection closes after the response.
+    ///
+    pub(super) async fn validate_token(token: &str) -> Result<(), std::io::Error> {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let response = async {
+            let stream = TcpStream::connect("id.twitch.tv:443").await?;
+            let domain = ServerName::try_from("id.twitch.tv")
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            let mut stream = connector.connect(domain, stream).await?;
+            let request = format!(
+                "GET /oauth2/validate HTTP/1.0\r\n\
+                Host: id.twitch.tv\r\n\
+                Authorization: OAuth {}\r\n\r\n",
+                token
+            );
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        }
+        .await
+        .map_err(|e| std::io::Error::new(e.kind(), format!("can't validate the token: {}", e)))?;
+
+        validated(&response)
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Validation {
+        #[serde(default)]
+        scopes: Vec<String>,
+    }
+
+    ///
+    /// Checks a response of the validate endpoint, a 200 whose `scopes` have `chat:read`.
+    ///
+    pub(super) fn validated(response: &str) -> Result<(), std::io::Error> {
+        let invalid = |e: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string());
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| invalid("malformed validate response"))?;
+        let status = head
+            .split(' ')
+            .nth(1)
+            .ok_or_else(|| invalid("malformed validate response"))?;
+        match status {
+            "200" => {}
+            "401" => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "token was revoked or is invalid",
+                ))
+            }
+            status => return Err(invalid(&format!("validate responded with {}", status))),
+        }
+        let validation: Validation =
+            serde_json::from_str(body).map_err(|_| invalid("malformed validate response"))?;
+        if !validation.scopes.iter().any(|scope| scope == "chat:read") {
+            return Err(invalid("token lacks the chat:read scope"));
+        }
+        Ok(())
     }
 
     async fn open_browser(url: &str) -> Result<std::process::ExitStatus, std::io::Error> {
@@ -130,7 +905,11 @@ mod http {
             .await
     }
 
-    fn start_webserver(token_tx: Sender<String>, mut shutdown_rx: Receiver<()>) -> JoinHandle<()> {
+    fn start_webserver(
+        listener: tokio::net::TcpListener,
+        token_tx: Sender<String>,
+        shutdown_rx: oneshot::Receiver<()>,
+    ) -> JoinHandle<()> {
         let state = Arc::new(token_tx);
         tokio::spawn(async move {
             // build our application with a single route
@@ -140,11 +919,9 @@ mod http {
                 .route("/script.js", get(serve_script))
                 .layer(Extension(state));
 
-            let listener = tokio::net::TcpListener::bind("0.0.0.0:4537").await.unwrap();
-
             axum::serve(listener, app)
                 .with_graceful_shutdown(async move {
-                    shutdown_rx.recv().await;
+                    let _ = shutdown_rx.await;
                 })
                 .await
                 .unwrap()
@@ -181,8 +958,151 @@ mod http {
     async fn handle_token_route(
         state: Extension<Arc<Sender<String>>>,
         Json(payload): Json<TokenBody>,
-    ) {
-        state.0.send(payload.token).await.unwrap();
-        "OK".to_string();
+    ) -> impl IntoResponse {
+        match state.0.try_send(payload.token) {
+            Ok(()) => (StatusCode::OK, "OK"),
+            Err(TrySendError::Full(_)) => (StatusCode::CONFLICT, "A token is already waiting"),
+            Err(TrySendError::Closed(_)) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "No longer waiting for a token",
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_time_of_utc() {
+        assert_eq!(unix_time("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(unix_time("2024-03-01T12:00:00Z"), Some(1709294400));
+        assert_eq!(unix_time("2024-02-29t23:59:59z"), Some(1709251199));
+    }
+
+    #[test]
+    fn unix_time_applies_offsets() {
+        assert_eq!(unix_time("2024-03-01T13:00:00+01:00"), Some(1709294400));
+        assert_eq!(unix_time("2024-03-01T06:30:00-05:30"), Some(1709294400));
+        assert_eq!(unix_time("2024-03-01 12:00:00+00:00"), Some(1709294400));
+        assert_eq!(unix_time("2024-03-01T12:00:00-00:00"), Some(1709294400));
+        // an offset can move the time across a day and a year
+        assert_eq!(unix_time("2024-01-01T01:00:00+02:00"), Some(1704063600));
+        assert_eq!(unix_time("2023-12-31T23:00:00-23:59"), Some(1704149940));
+    }
+
+    #[test]
+    fn unix_time_ignores_fractional_seconds() {
+        assert_eq!(unix_time("2024-03-01T12:00:00.5Z"), Some(1709294400));
+        assert_eq!(
+            unix_time("2024-03-01T13:00:00.123456+01:00"),
+            Some(1709294400)
+        );
+        assert_eq!(
+            unix_time("2024-03-01T06:30:00.999999999-05:30"),
+            Some(1709294400)
+        );
+        assert_eq!(unix_time("1970-01-01T00:00:00.000001Z"), Some(0));
+    }
+
+    #[test]
+    fn unix_time_rejects_malformed_input() {
+        for time in [
+            "",
+            "2024-03-01",
+            "2024-03-01T12:00:00",
+            "2024/03/01T12:00:00Z",
+            "2024-03-01X12:00:00Z",
+            "2024-13-01T12:00:00Z",
+            "2024-03-01T24:00:00Z",
+            "2024-03-01T12:00:00.Z",
+            "2024-03-01T12:00:00+0100",
+            "2024-03-01T12:00:00+1:00",
+            "+024-03-01T12:00:00Z",
+            "2024-03-01T12:00:00Zjunk",
+        ] {
+            assert_eq!(unix_time(time), None, "{}", time);
+        }
+    }
+
+    #[test]
+    fn expired_cli_token_is_rejected() {
+        let env = "ACCESSTOKEN=abc123\nTOKENSCOPES=[chat:read chat:edit]\n";
+        let expired = format!("{}TOKENEXPIRATION=2001-01-01T00:00:00Z", env);
+        let valid = format!("{}TOKENEXPIRATION=2999-01-01T00:00:00Z", env);
+
+        assert_eq!(unexpired_cli_token(&expired), Err("token expired"));
+        assert_eq!(unexpired_cli_token(&valid), Ok("abc123".to_string()));
+        assert_eq!(
+            unexpired_cli_token("ACCESSTOKEN=abc123\nTOKENSCOPES=[]"),
+            Err("token lacks the chat:read scope")
+        );
+    }
+
+    #[test]
+    fn validate_responses() {
+        let ok = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n\
+            {\"client_id\":\"id\",\"login\":\"user\",\"scopes\":[\"chat:edit\",\"chat:read\"]}";
+        let no_scope = "HTTP/1.1 200 OK\r\n\r\n{\"scopes\":[]}";
+        let revoked = "HTTP/1.1 401 Unauthorized\r\n\r\n\
+            {\"status\":401,\"message\":\"invalid access token\"}";
+
+        assert!(http::validated(ok).is_ok());
+        assert_eq!(
+            http::validated(no_scope).unwrap_err().kind(),
+            tokio::io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            http::validated(revoked).unwrap_err().kind(),
+            tokio::io::ErrorKind::PermissionDenied
+        );
+        assert!(http::validated("").is_err());
+    }
+
+    #[test]
+    fn missing_fields_get_their_defaults() {
+        let config: Config = r#"{"channel":"channel","capabilities":{"tags":false}}"#
+            .parse()
+            .unwrap();
+        assert_eq!(config.channel.as_deref(), Some("channel"));
+        assert_eq!(config.oauth, None);
+        assert_eq!(config.send_behavior, SendBehavior::Error);
+        assert_eq!(config.antidupe, AntiDupeMode::default());
+        assert!(config.channels.is_empty());
+        assert!(config.extra.is_empty());
+        // within a section too
+        assert_eq!(
+            config.capabilities,
+            CapabilitySet {
+                tags: false,
+                ..CapabilitySet::default()
+            }
+        );
+
+        let empty: Config = "{}".parse().unwrap();
+        assert_eq!(empty.channel, None);
+        assert_eq!(empty.capabilities, CapabilitySet::default());
+    }
+
+    #[tokio::test]
+    async fn unknown_fields_survive_a_save() {
+        let dir = env::temp_dir().join(format!("ttvy_core-config-{}", std::process::id()));
+        let store = FileStore::new(dir.join("state.json"));
+        let mut config: Config = r#"{"channel":"channel","from_a_newer_version":{"a":[1,2]}}"#
+            .parse()
+            .unwrap();
+        config.nick = Some("botnick".to_string());
+        config.save_to(&store).await.unwrap();
+
+        let saved = fs::read_to_string(store.path()).await.unwrap();
+        fs::remove_dir_all(&dir).await.unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&saved).unwrap();
+        assert_eq!(
+            saved["from_a_newer_version"],
+            serde_json::json!({"a": [1, 2]})
+        );
+        assert_eq!(saved["channel"], "channel");
+        assert_eq!(saved["nick"], "botnick");
     }
 }
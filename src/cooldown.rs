@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+///
+/// Keyed cooldowns, eg one per bot command.
+///
+#[derive(Debug, Default)]
+pub struct Cooldowns {
+    last_used: HashMap<String, Instant>,
+}
+
+impl Cooldowns {
+    ///
+    /// Marks `key` as used and returns `true`, unless it was already used within `duration`.
+    ///
+    pub fn try_use(&mut self, key: &str, duration: Duration) -> bool {
+        let now = Instant::now();
+        match self.last_used.get(key) {
+            Some(last) if now.duration_since(*last) < duration => false,
+            _ => {
+                self.last_used.insert(key.to_string(), now);
+                true
+            }
+        }
+    }
+
+    pub fn reset(&mut self, key: &str) {
+        self.last_used.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn a_key_is_usable_again_once_its_cooldown_passed() {
+        let mut cooldowns = Cooldowns::default();
+        let cooldown = Duration::from_secs(30);
+        assert!(cooldowns.try_use("!dice", cooldown));
+        assert!(!cooldowns.try_use("!dice", cooldown));
+        // keys don't share a cooldown
+        assert!(cooldowns.try_use("!help", cooldown));
+
+        tokio::time::advance(cooldown - Duration::from_millis(1)).await;
+        assert!(!cooldowns.try_use("!dice", cooldown));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(cooldowns.try_use("!dice", cooldown));
+
+        cooldowns.reset("!dice");
+        assert!(cooldowns.try_use("!dice", cooldown));
+    }
+}
@@ -0,0 +1,53 @@
+///
+/// An emote of a message's `emotes` tag, with where it appears in the message.
+/// The ranges are inclusive and count characters, not bytes.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Emote {
+    pub id: String,
+    pub ranges: Vec<(usize, usize)>,
+}
+
+impl Emote {
+    pub fn url(&self, format: EmoteFormat, theme: EmoteTheme) -> String {
+        emote_url(&self.id, format, theme)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmoteFormat {
+    Static,
+    /// Only exists for animated emotes.
+    Animated,
+    /// Animated if the emote is, static otherwise.
+    #[default]
+    Default,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmoteTheme {
+    Light,
+    #[default]
+    Dark,
+}
+
+///
+/// The CDN URL of an emote in its largest size:
+/// `https://static-cdn.jtvnw.net/emoticons/v2/<id>/<format>/<theme>/3.0`,
+/// where format is `static`, `animated` or `default` and theme is `light` or `dark`.
+///
+pub fn emote_url(id: &str, format: EmoteFormat, theme: EmoteTheme) -> String {
+    let format = match format {
+        EmoteFormat::Static => "static",
+        EmoteFormat::Animated => "animated",
+        EmoteFormat::Default => "default",
+    };
+    let theme = match theme {
+        EmoteTheme::Light => "light",
+        EmoteTheme::Dark => "dark",
+    };
+    format!(
+        "https://static-cdn.jtvnw.net/emoticons/v2/{}/{}/{}/3.0",
+        id, format, theme
+    )
+}
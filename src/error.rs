@@ -0,0 +1,23 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError {
+    /// No channel has been joined yet, or the chat has left it.
+    NotConnected,
+    /// The connection task is gone and can no longer take messages.
+    Closed,
+    /// `SendBehavior::Queue` already holds as many messages as it allows.
+    QueueFull,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::NotConnected => write!(f, "not connected to a channel"),
+            SendError::Closed => write!(f, "connection closed"),
+            SendError::QueueFull => write!(f, "send queue full"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::message::ChatMessage;
+use super::policy::NoticeAction;
+
+// messages are by far the most common event, so they aren't boxed to keep the others small
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum ChatEvent {
+    Message(ChatMessage),
+    Sub(SubEvent),
+    SubGift(SubGiftEvent),
+    SubMysteryGift(SubMysteryGiftEvent),
+    PayForward(PayForwardEvent),
+    ViewerMilestone(ViewerMilestoneEvent),
+    BitsBadgeTier(BitsBadgeTierEvent),
+    Announcement(AnnouncementEvent),
+    Raid(RaidEvent),
+    /// A USERNOTICE without a dedicated event, so new Twitch types still come through.
+    UserNotice(UserNotice),
+    RoleChanged(RoleChanged),
+    /// A `/shoutout` we gave went through, other shoutout feedback comes as a `Notice`.
+    Shoutout(Shoutout),
+    Notice(Notice),
+    /// A CLEARCHAT, a ban or timeout of a user or the whole chat being cleared.
+    ClearChat(ClearChat),
+    /// A CLEARMSG, a single message being deleted.
+    ClearMsg(ClearMsg),
+    /// The USERSTATE/GLOBALUSERSTATE color of the authenticated user changed, eg after `/color`.
+    SelfColorChanged(Option<String>),
+    /// Emote sets that appeared in the authenticated user's `emote-sets` since the last state.
+    EmoteSetsAdded(Vec<String>),
+    /// The merged ROOMSTATE of a channel, sent on join and whenever a setting changes.
+    RoomState(RoomState),
+    /// A connection finished registration and had its capabilities acknowledged.
+    Connected(ConnectedInfo),
+    /// The connection ended, the supervisor reconnects unless the reason says otherwise.
+    Disconnected(DisconnectReason),
+    /// Incoming events were dropped because the consumer doesn't keep up, sent at most every 10s.
+    Dropped(DroppedEvents),
+    /// A heartbeat sent every `status_interval_ms`, when that is set.
+    Status(Status),
+}
+
+///
+/// How many events were dropped since the previous `Dropped` warning, over the `window` since
+/// the first of them. A warning sent right on the first drop of a burst has a zero `window`.
+/// Events the consumer had no room for and ones pushed out of a full pause buffer are counted
+/// together.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DroppedEvents {
+    pub count: u64,
+    pub window: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    /// Connecting or waiting for the server to finish registration.
+    Connecting,
+    /// The server sent its `001` welcome.
+    Connected,
+}
+
+///
+/// A summary of a connection that came up, eg for "Connected as X to #Y (tags, commands)".
+///
+#[derive(Debug, Clone)]
+pub struct ConnectedInfo {
+    pub endpoint: String,
+    /// The capabilities the server acknowledged, eg `twitch.tv/tags`
+    pub capabilities: Vec<String>,
+    /// `None` when anonymous
+    pub login: Option<String>,
+    /// The channels the connection joined, with the casing they were joined with
+    pub channels: Vec<String>,
+    pub anonymous: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum DisconnectReason {
+    Shutdown,
+    /// The websocket failed or was closed.
+    Connection(String),
+    /// The server asked for a reconnect with RECONNECT.
+    Reconnect,
+    /// The server didn't acknowledge the requested capabilities in time,
+    /// leaving the connection open but useless.
+    HandshakeTimeout,
+    /// The account logged in from somewhere else and the server dropped this connection.
+    /// The supervisor stops instead of fighting the other instance over the login.
+    LoginConflict,
+    /// A NOTICE whose policy action ended the connection.
+    Notice {
+        msg_id: String,
+        action: NoticeAction,
+    },
+}
+
+///
+/// The `msg-param-sub-plan` of a sub event.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubPlan {
+    Prime,
+    Tier1,
+    Tier2,
+    Tier3,
+}
+
+impl SubPlan {
+    pub fn from_tag(value: &str) -> Option<Self> {
+        match value {
+            "Prime" => Some(SubPlan::Prime),
+            "1000" => Some(SubPlan::Tier1),
+            "2000" => Some(SubPlan::Tier2),
+            "3000" => Some(SubPlan::Tier3),
+            _ => None,
+        }
+    }
+}
+
+///
+/// USERNOTICE `msg-id=sub` or `msg-id=resub`.
+///
+#[derive(Debug, Clone)]
+pub struct SubEvent {
+    pub user: String,
+    pub user_login: String,
+    pub is_resub: bool,
+    pub sub_plan: Option<SubPlan>,
+    /// The channel-specific name of the plan, eg "Channel Subscription (somechannel)".
+    pub sub_plan_name: String,
+    pub cumulative_months: u32,
+    pub streak_months: Option<u32>,
+    pub message: Option<String>,
+}
+
+///
+/// USERNOTICE `msg-id=subgift`, a single gifted sub.
+///
+#[derive(Debug, Clone)]
+pub struct SubGiftEvent {
+    pub gifter: String,
+    pub gifter_login: String,
+    pub recipient_display_name: String,
+    pub recipient_id: String,
+    pub sub_plan: Option<SubPlan>,
+    pub sub_plan_name: String,
+    pub gift_months: u32,
+    pub months: u32,
+}
+
+///
+/// USERNOTICE `msg-id=submysterygift`, sent before the individual `subgift` notices
+/// of a community gift.
+///
+#[derive(Debug, Clone)]
+pub struct SubMysteryGiftEvent {
+    pub gifter: String,
+    pub gifter_login: String,
+    pub sub_plan: Option<SubPlan>,
+    pub count: u32,
+}
+
+///
+/// USERNOTICE `msg-id=standardpayforward` or `msg-id=communitypayforward`,
+/// a user paying forward a gift sub they received.
+///
+#[derive(Debug, Clone)]
+pub struct PayForwardEvent {
+    pub user: String,
+    pub user_login: String,
+    pub prior_gifter: Option<String>,
+    /// Only set for `standardpayforward`, community pay forwards have no single recipient.
+    pub recipient: Option<String>,
+}
+
+///
+/// USERNOTICE `msg-id=viewermilestone`, eg a watch streak.
+///
+#[derive(Debug, Clone)]
+pub struct ViewerMilestoneEvent {
+    pub user: String,
+    pub user_login: String,
+    pub category: String,
+    pub value: u32,
+    pub message: Option<String>,
+}
+
+///
+/// USERNOTICE `msg-id=bitsbadgetier`.
+///
+#[derive(Debug, Clone)]
+pub struct BitsBadgeTierEvent {
+    pub user: String,
+    pub user_login: String,
+    pub threshold: u32,
+    pub message: Option<String>,
+}
+
+///
+/// USERNOTICE `msg-id=announcement`.
+///
+#[derive(Debug, Clone)]
+pub struct AnnouncementEvent {
+    pub user: String,
+    pub user_login: String,
+    /// `PRIMARY`, `BLUE`, `GREEN`, `ORANGE` or `PURPLE`.
+    pub color: Option<String>,
+    pub message: String,
+}
+
+///
+/// USERNOTICE `msg-id=raid`, `user` raided the channel with `viewer_count` viewers.
+///
+#[derive(Debug, Clone)]
+pub struct RaidEvent {
+    pub user: String,
+    pub user_login: String,
+    pub viewer_count: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct UserNotice {
+    pub msg_id: String,
+    pub user: String,
+    pub user_login: String,
+    pub system_msg: String,
+    pub message: Option<String>,
+    /// All `msg-param-*` tags, keyed by the full tag name.
+    pub params: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Moderator,
+    Vip,
+}
+
+///
+/// Parsed from the `mod_success`/`unmod_success`/`vip_success`/`unvip_success` NOTICEs.
+///
+#[derive(Debug, Clone)]
+pub struct RoleChanged {
+    pub user: String,
+    pub role: Role,
+    pub added: bool,
+}
+
+///
+/// Parsed from the `shoutout_success` NOTICE, eg "You gave a shoutout to forsen.".
+///
+#[derive(Debug, Clone)]
+pub struct Shoutout {
+    pub channel: Option<String>,
+    /// The shouted-out channel, as written in the notice
+    pub target: String,
+}
+
+///
+/// `target_login` is `None` when the whole chat was cleared.
+/// A ban has no `ban_duration`, a timeout lasts `ban_duration`.
+///
+#[derive(Debug, Clone)]
+pub struct ClearChat {
+    pub channel: String,
+    pub target_login: Option<String>,
+    pub target_user_id: Option<String>,
+    pub ban_duration: Option<Duration>,
+}
+
+impl ClearChat {
+    ///
+    /// Whether a user was banned or timed out, rather than the chat cleared.
+    ///
+    pub fn is_ban(&self) -> bool {
+        self.target_login.is_some()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClearMsg {
+    pub channel: String,
+    /// The author of the deleted message.
+    pub login: String,
+    pub target_msg_id: String,
+    pub message: String,
+}
+
+///
+/// A CLEARCHAT or CLEARMSG kept for `Chat::recent_mod_actions`.
+///
+#[derive(Debug, Clone)]
+pub enum ModAction {
+    ClearChat(ClearChat),
+    ClearMsg(ClearMsg),
+}
+
+///
+/// A message or moderation action of `Chat::timeline`, in the order they were received.
+///
+// most entries are messages, like in `ChatEvent`
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum TimelineEntry {
+    /// `removed` is the CLEARMSG that deleted the message, or the CLEARCHAT that
+    /// banned or timed out its author or cleared the chat after it was sent.
+    Message {
+        message: ChatMessage,
+        removed: Option<ModAction>,
+    },
+    /// A ban, timeout or chat clear, or the deletion of a message no longer in the timeline.
+    ModAction(ModAction),
+}
+
+///
+/// A NOTICE the crate has no dedicated event for.
+///
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub channel: Option<String>,
+    /// `parse::LOGIN_FAILED` for a rejected token, which Twitch sends without one
+    pub msg_id: Option<String>,
+    pub message: String,
+}
+
+///
+/// The authenticated user, as reported by USERSTATE and GLOBALUSERSTATE.
+///
+#[derive(Debug, Clone, Default)]
+pub struct SelfUser {
+    pub display_name: String,
+    pub color: Option<String>,
+    /// Only sent with GLOBALUSERSTATE.
+    pub user_id: Option<String>,
+    pub emote_sets: Vec<String>,
+}
+
+///
+/// Channel settings from ROOMSTATE. Updates only carry the changed setting,
+/// so fields that were never sent are `None`.
+///
+#[derive(Debug, Clone, Default)]
+pub struct RoomState {
+    pub channel: String,
+    pub room_id: Option<String>,
+    pub emote_only: Option<bool>,
+    /// Minutes a user has to follow before chatting, `-1` when followers-only is off.
+    pub followers_only: Option<i64>,
+    pub r9k: Option<bool>,
+    /// Seconds between messages of a user, `0` when slow mode is off.
+    pub slow: Option<u64>,
+    pub subs_only: Option<bool>,
+    /// The `broadcaster-lang` tag, eg `en`. Twitch rarely fills it anymore,
+    /// so an empty tag is treated like a missing one.
+    pub broadcaster_lang: Option<String>,
+}
+
+impl RoomState {
+    pub fn merge(&mut self, update: RoomState) {
+        self.channel = update.channel;
+        self.room_id = update.room_id.or(self.room_id.take());
+        self.emote_only = update.emote_only.or(self.emote_only);
+        self.followers_only = update.followers_only.or(self.followers_only);
+        self.r9k = update.r9k.or(self.r9k);
+        self.slow = update.slow.or(self.slow);
+        self.subs_only = update.subs_only.or(self.subs_only);
+        self.broadcaster_lang = update.broadcaster_lang.or(self.broadcaster_lang.take());
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelStatus {
+    pub channel: String,
+    pub joined: bool,
+    pub room_state: Option<RoomState>,
+    /// Chat messages received in the last minute.
+    pub messages_per_minute: usize,
+}
+
+///
+/// A snapshot of the chat's health, see `Chat::status`.
+///
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub connection_state: ConnectionState,
+    /// Time since the last received frame, see `Chat::idle_duration`.
+    pub idle: Duration,
+    /// Chat messages received in the last minute, over all channels.
+    pub messages_per_minute: usize,
+    pub channels: Vec<ChannelStatus>,
+}
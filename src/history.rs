@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use tokio::fs;
+
+use super::chat::ChatMessage;
+use super::config::write_atomic;
+use super::parse;
+
+///
+/// Where the last `size` messages of each channel are kept between runs,
+/// one reconstructed PRIVMSG line per message, oldest first.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HistoryFile {
+    pub path: PathBuf,
+    pub size: usize,
+}
+
+impl HistoryFile {
+    ///
+    /// The saved messages per channel, nothing if there is no file yet.
+    /// Lines that don't parse, eg the last one of a file cut short, are skipped.
+    ///
+    pub async fn load(&self) -> HashMap<String, Vec<ChatMessage>> {
+        let mut history: HashMap<String, Vec<ChatMessage>> = HashMap::new();
+        let data = match fs::read_to_string(&self.path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == tokio::io::ErrorKind::NotFound => return history,
+            Err(e) => {
+                eprintln!("Failed to load history ({}): {}", self.path.display(), e);
+                return history;
+            }
+        };
+
+        let mut skipped = 0;
+        for line in data.lines().filter(|line| !line.trim().is_empty()) {
+            match parse::parse_privmsg(line) {
+                Some(message) if message.channel.is_some() => {
+                    let channel = message.channel.clone().unwrap();
+                    history.entry(channel).or_default().push(message);
+                }
+                _ => skipped += 1,
+            }
+        }
+        if skipped > 0 {
+            eprintln!(
+                "Skipped {} unreadable lines of history ({})",
+                skipped,
+                self.path.display()
+            );
+        }
+
+        for messages in history.values_mut() {
+            let excess = messages.len().saturating_sub(self.size);
+            messages.drain(..excess);
+        }
+        history
+    }
+
+    ///
+    /// Written through a temporary file like `FileStore::save`.
+    ///
+    pub async fn save(
+        &self,
+        history: &HashMap<String, Vec<ChatMessage>>,
+    ) -> Result<(), tokio::io::Error> {
+        let mut channels: Vec<&String> = history.keys().collect();
+        channels.sort();
+
+        let mut data = String::new();
+        for channel in channels {
+            let messages = &history[channel];
+            let skip = messages.len().saturating_sub(self.size);
+            for message in messages.iter().skip(skip) {
+                data.push_str(&message.to_irc_line(channel));
+                data.push('\n');
+            }
+        }
+        write_atomic(&self.path, data).await
+    }
+}
@@ -1,3 +1,24 @@
+#[cfg(feature = "client")]
 pub mod chat;
+#[cfg(feature = "client")]
 mod chat_controller;
+mod color;
+#[cfg(feature = "client")]
 mod config;
+#[cfg(feature = "client")]
+mod cooldown;
+mod emote;
+#[cfg(feature = "client")]
+mod error;
+mod event;
+#[cfg(feature = "client")]
+mod history;
+mod message;
+#[cfg(feature = "client")]
+mod outgoing;
+pub mod parse;
+mod policy;
+#[cfg(feature = "client")]
+mod subscription;
+#[cfg(feature = "client")]
+mod transport;
@@ -0,0 +1,445 @@
+use super::color::Color;
+use super::emote::Emote;
+use super::parse;
+
+#[derive(Debug, Clone, Default)]
+pub struct ChatMessage {
+    pub author: String,
+    /// The login of the author, `author` is the display name which can differ in more than casing
+    pub login: Option<String>,
+    pub color: Option<String>,
+    pub message: String,
+    /// The channel as Twitch sends it, lowercase and without the `#`
+    pub channel: Option<String>,
+    /// The channel with the casing it was joined with, for display
+    pub display_channel: Option<String>,
+    pub id: Option<String>,
+    pub reply: Option<ReplyInfo>,
+    pub hype_chat: Option<HypeChat>,
+    pub emotes: Vec<Emote>,
+    /// Whether the emotes make up the whole message, for rendering it larger
+    pub is_emote_only: bool,
+    /// The AutoMod classifications of the `flags` tag, usually empty
+    pub flags: Vec<ContentFlag>,
+    /// The `room-id` of the joined channel the message was received in
+    pub room_id: Option<String>,
+    /// Where a message of a shared chat session was sent, `None` for the joined channel
+    pub source: Option<SharedChatSource>,
+    /// The `client-nonce` the sender tagged the message with, to match a sent message to its echo
+    pub client_nonce: Option<String>,
+}
+
+///
+/// Builds a `ChatMessage` that wasn't received, eg for tests or a message shown by the client
+/// itself. Fields that aren't set are empty, `None` or `false`, so adding a field to
+/// `ChatMessage` doesn't break code building one.
+///
+#[derive(Debug, Clone, Default)]
+pub struct ChatMessageBuilder {
+    message: ChatMessage,
+}
+
+impl ChatMessageBuilder {
+    pub fn author(mut self, author: impl Into<String>) -> Self {
+        self.message.author = author.into();
+        self
+    }
+
+    pub fn login(mut self, login: impl Into<String>) -> Self {
+        self.message.login = Some(login.into());
+        self
+    }
+
+    pub fn message(mut self, message: impl Into<String>) -> Self {
+        self.message.message = message.into();
+        self
+    }
+
+    ///
+    /// Normalized like a received channel, `display_channel` keeps it as given.
+    ///
+    pub fn channel(mut self, channel: &str) -> Self {
+        self.message.channel = Some(parse::normalize_channel(channel));
+        self.message.display_channel = Some(channel.trim().trim_start_matches('#').to_string());
+        self
+    }
+
+    ///
+    /// Stored as `#RRGGBB`, like the `color` tag.
+    ///
+    pub fn color(mut self, color: Color) -> Self {
+        self.message.color = Some(color.hex());
+        self
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.message.id = Some(id.into());
+        self
+    }
+
+    pub fn reply(mut self, reply: ReplyInfo) -> Self {
+        self.message.reply = Some(reply);
+        self
+    }
+
+    pub fn hype_chat(mut self, hype_chat: HypeChat) -> Self {
+        self.message.hype_chat = Some(hype_chat);
+        self
+    }
+
+    pub fn emote(mut self, emote: Emote) -> Self {
+        self.message.emotes.push(emote);
+        self
+    }
+
+    pub fn emote_only(mut self, is_emote_only: bool) -> Self {
+        self.message.is_emote_only = is_emote_only;
+        self
+    }
+
+    pub fn flag(mut self, flag: ContentFlag) -> Self {
+        self.message.flags.push(flag);
+        self
+    }
+
+    pub fn room_id(mut self, room_id: impl Into<String>) -> Self {
+        self.message.room_id = Some(room_id.into());
+        self
+    }
+
+    pub fn source(mut self, source: SharedChatSource) -> Self {
+        self.message.source = Some(source);
+        self
+    }
+
+    pub fn client_nonce(mut self, client_nonce: impl Into<String>) -> Self {
+        self.message.client_nonce = Some(client_nonce.into());
+        self
+    }
+
+    pub fn build(self) -> ChatMessage {
+        self.message
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplyParent {
+    pub msg_id: String,
+    pub user_login: String,
+    pub display_name: String,
+}
+
+///
+/// The `reply-parent-*` tags of a reply, and the `reply-thread-parent-*` tags
+/// pointing at the first message of the thread.
+///
+#[derive(Debug, Clone)]
+pub struct ReplyInfo {
+    pub parent: ReplyParent,
+    pub parent_body: String,
+    pub thread_parent: Option<ReplyParent>,
+}
+
+///
+/// The `pinned-chat-paid-*` tags of a paid pinned message (Hype Chat).
+/// `amount` is in the currency's smallest unit, eg cents, with `exponent` decimal places.
+///
+#[derive(Debug, Clone)]
+pub struct HypeChat {
+    pub amount: u64,
+    pub currency: String,
+    pub exponent: u32,
+    /// `ONE` to `TEN`
+    pub level: String,
+}
+
+///
+/// The `source-*` tags of a message sent to another channel of a shared chat session.
+/// Twitch sends the source room's id, not its name.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedChatSource {
+    pub room_id: String,
+    /// The id of the message in the source channel, which `id` doesn't match
+    pub msg_id: Option<String>,
+    /// Whether the message was only shown in the source channel, eg a channel point reward
+    pub only: bool,
+}
+
+///
+/// An AutoMod classification of part of a message, from its `flags` tag,
+/// eg `0-4:P.6,6-10:A.7/P.6`, with one flag per category of a range.
+/// The range is inclusive and counts characters, like `Emote::ranges`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentFlag {
+    pub start: usize,
+    pub end: usize,
+    pub category: FlagCategory,
+    /// How sure AutoMod is, `1` to `7`
+    pub severity: u8,
+}
+
+///
+/// The category codes of the `flags` tag:
+/// `A` aggressive, `I` identity based hate, `P` profanity, `S` sexual.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FlagCategory {
+    Aggressive,
+    Identity,
+    Profanity,
+    Sexual,
+    /// A code not listed above, as sent
+    Other(String),
+}
+
+impl FlagCategory {
+    pub fn from_code(code: &str) -> Self {
+        match code {
+            "A" => FlagCategory::Aggressive,
+            "I" => FlagCategory::Identity,
+            "P" => FlagCategory::Profanity,
+            "S" => FlagCategory::Sexual,
+            other => FlagCategory::Other(other.to_string()),
+        }
+    }
+
+    pub fn code(&self) -> &str {
+        match self {
+            FlagCategory::Aggressive => "A",
+            FlagCategory::Identity => "I",
+            FlagCategory::Profanity => "P",
+            FlagCategory::Sexual => "S",
+            FlagCategory::Other(code) => code,
+        }
+    }
+}
+
+impl HypeChat {
+    ///
+    /// The paid amount in whole units of the currency, eg 1.5 for 150 cents.
+    ///
+    pub fn value(&self) -> f64 {
+        self.amount as f64 / 10f64.powi(self.exponent as i32)
+    }
+}
+
+impl ChatMessage {
+    pub fn builder() -> ChatMessageBuilder {
+        ChatMessageBuilder::default()
+    }
+
+    ///
+    /// Reconstructs the PRIVMSG line for this message, tagged with whatever is known.
+    /// Without a `login` the lowercased `author` stands in for it.
+    ///
+    pub fn to_irc_line(&self, channel: &str) -> String {
+        let login = self
+            .login
+            .clone()
+            .unwrap_or_else(|| self.author.to_lowercase());
+        let mut tags = vec![];
+        if let Some(client_nonce) = self.client_nonce.as_ref() {
+            tags.push(format!("client-nonce={}", client_nonce));
+        }
+        if let Some(color) = self.color.as_ref() {
+            tags.push(format!("color={}", color));
+        }
+        tags.push(format!("display-name={}", self.author));
+        if self.is_emote_only {
+            tags.push("emote-only=1".to_string());
+        }
+        if !self.emotes.is_empty() {
+            let emotes: Vec<String> = self
+                .emotes
+                .iter()
+                .map(|emote| {
+                    let ranges: Vec<String> = emote
+                        .ranges
+                        .iter()
+                        .map(|(start, end)| format!("{}-{}", start, end))
+                        .collect();
+                    format!("{}:{}", emote.id, ranges.join(","))
+                })
+                .collect();
+            tags.push(format!("emotes={}", emotes.join("/")));
+        }
+        if !self.flags.is_empty() {
+            let mut ranges: Vec<String> = vec![];
+            let mut last_range = None;
+            for flag in self.flags.iter() {
+                let category = format!("{}.{}", flag.category.code(), flag.severity);
+                match ranges.last_mut() {
+                    Some(range) if last_range == Some((flag.start, flag.end)) => {
+                        range.push('/');
+                        range.push_str(&category);
+                    }
+                    _ => ranges.push(format!("{}-{}:{}", flag.start, flag.end, category)),
+                }
+                last_range = Some((flag.start, flag.end));
+            }
+            tags.push(format!("flags={}", ranges.join(",")));
+        }
+        if let Some(id) = self.id.as_ref() {
+            tags.push(format!("id={}", id));
+        }
+        if let Some(hype_chat) = self.hype_chat.as_ref() {
+            tags.push(format!("pinned-chat-paid-amount={}", hype_chat.amount));
+            tags.push(format!("pinned-chat-paid-currency={}", hype_chat.currency));
+            tags.push(format!("pinned-chat-paid-exponent={}", hype_chat.exponent));
+            tags.push(format!("pinned-chat-paid-level={}", hype_chat.level));
+        }
+        if let Some(reply) = self.reply.as_ref() {
+            let ReplyInfo {
+                parent,
+                parent_body,
+                thread_parent,
+            } = reply;
+            tags.push(format!("reply-parent-display-name={}", parent.display_name));
+            tags.push(format!(
+                "reply-parent-msg-body={}",
+                parse::escape_tag_value(parent_body)
+            ));
+            tags.push(format!("reply-parent-msg-id={}", parent.msg_id));
+            tags.push(format!("reply-parent-user-login={}", parent.user_login));
+            if let Some(thread_parent) = thread_parent {
+                tags.push(format!(
+                    "reply-thread-parent-display-name={}",
+                    thread_parent.display_name
+                ));
+                tags.push(format!(
+                    "reply-thread-parent-msg-id={}",
+                    thread_parent.msg_id
+                ));
+                tags.push(format!(
+                    "reply-thread-parent-user-login={}",
+                    thread_parent.user_login
+                ));
+            }
+        }
+        if let Some(room_id) = self.room_id.as_ref() {
+            tags.push(format!("room-id={}", room_id));
+        }
+        if let Some(source) = self.source.as_ref() {
+            if let Some(msg_id) = source.msg_id.as_ref() {
+                tags.push(format!("source-id={}", msg_id));
+            }
+            tags.push(format!("source-only={}", u8::from(source.only)));
+            tags.push(format!("source-room-id={}", source.room_id));
+        }
+
+        format!(
+            "@{} :{}!{}@{}.tmi.twitch.tv PRIVMSG #{} :{}",
+            tags.join(";"),
+            login,
+            login,
+            login,
+            channel.trim_start_matches('#'),
+            self.message
+        )
+    }
+
+    ///
+    /// Parses a raw PRIVMSG line, with or without tags.
+    ///
+    pub fn from_irc_line(line: &str) -> Option<Self> {
+        parse::parse_privmsg(line)
+    }
+
+    ///
+    /// The `room-id` of the channel the message was sent in,
+    /// the joined channel's unless it came from another channel of a shared chat session.
+    ///
+    pub fn source_room_id(&self) -> Option<&str> {
+        match self.source.as_ref() {
+            Some(source) => Some(&source.room_id),
+            None => self.room_id.as_deref(),
+        }
+    }
+
+    ///
+    /// Whether the message was sent in another channel of a shared chat session.
+    ///
+    pub fn is_from_shared_chat(&self) -> bool {
+        self.source.is_some()
+    }
+
+    ///
+    /// The `color` of the author, `None` if they never set one.
+    ///
+    pub fn display_color(&self) -> Option<Color> {
+        self.color.as_ref()?.parse().ok()
+    }
+
+    ///
+    /// Whether the message starts with one of `prefixes`, eg a command for another bot.
+    /// Empty prefixes match nothing.
+    ///
+    pub fn has_prefix(&self, prefixes: &[impl AsRef<str>]) -> bool {
+        let message = self.message.trim_start();
+        prefixes.iter().any(|prefix| {
+            let prefix = prefix.as_ref();
+            !prefix.is_empty() && message.starts_with(prefix)
+        })
+    }
+
+    ///
+    /// The `http(s)://` and `www.` links in the message, without surrounding punctuation.
+    ///
+    pub fn urls(&self) -> Vec<&str> {
+        self.message
+            .split_whitespace()
+            .map(|word| {
+                word.trim_start_matches(['(', '<', '[', '"', '\''])
+                    .trim_end_matches(['.', ',', '!', '?', ':', ';', ')', '>', ']', '"', '\''])
+            })
+            .filter(|word| {
+                let lower = word.to_ascii_lowercase();
+                (lower.starts_with("http://")
+                    || lower.starts_with("https://")
+                    || lower.starts_with("www."))
+                    && !lower.ends_with("://")
+            })
+            .collect()
+    }
+
+    ///
+    /// The users mentioned with `@user`, without the `@`.
+    ///
+    pub fn mentions_list(&self) -> Vec<&str> {
+        self.message
+            .split_whitespace()
+            .filter_map(|word| {
+                word.trim_start_matches(['(', '[', '"', '\''])
+                    .strip_prefix('@')
+            })
+            .map(|name| {
+                let end = name
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(name.len());
+                &name[..end]
+            })
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn irc_line_keeps_the_login_of_a_localized_name() {
+        let line = "@color=#1E90FF;display-name=日本語;id=abc :nihongo!nihongo@nihongo.tmi.twitch.tv PRIVMSG #channel :hello";
+        let message = parse::parse_privmsg(line).unwrap();
+        assert_eq!(message.author, "日本語");
+        assert_eq!(message.login.as_deref(), Some("nihongo"));
+
+        let saved = message.to_irc_line("channel");
+        assert!(saved.contains(" :nihongo!nihongo@nihongo.tmi.twitch.tv PRIVMSG #channel :hello"));
+        let loaded = parse::parse_privmsg(&saved).unwrap();
+        assert_eq!(loaded.author, "日本語");
+        assert_eq!(loaded.login.as_deref(), Some("nihongo"));
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+
+use std::time::Duration;
+
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use super::error::SendError;
+
+pub type OutgoingSender = Sender<(Priority, Outgoing)>;
+
+///
+/// Twitch allows about 3 whispers a second and 100 a minute,
+/// so nothing is sent sooner than this after a whisper.
+///
+pub const WHISPER_SPACING: Duration = Duration::from_millis(600);
+
+///
+/// Where a message goes, see `Chat::send_to_target`.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// A channel, which doesn't have to be the joined one.
+    Channel(String),
+    /// A user, sent as a `/w` command on the joined channel.
+    Whisper(String),
+}
+
+///
+/// A queued message, `target` is `None` for the joined channel,
+/// or a channel switch that waits its turn behind the messages before it.
+///
+#[derive(Debug, Clone)]
+pub enum Outgoing {
+    Message {
+        target: Option<Target>,
+        text: String,
+        /// How long at least until the next message, the send spacing if that is longer
+        spacing: Duration,
+    },
+    SwitchChannel(String),
+}
+
+///
+/// Order in which queued outgoing messages are sent while the send spacing holds them back.
+/// Messages of the same priority keep the order they were sent in.
+/// A channel switch goes after everything queued before it, whatever the priority.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    fn index(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+///
+/// A handle for queueing messages from other tasks, see `Chat::sender`.
+/// Messages go through the same queue, spacing and anti-dupe as `Chat::send`.
+/// Fails with `SendError::Closed` once the chat left or joined again.
+///
+#[derive(Debug, Clone)]
+pub struct MessageSender {
+    tx: OutgoingSender,
+}
+
+impl MessageSender {
+    pub(crate) fn new(tx: OutgoingSender) -> Self {
+        Self { tx }
+    }
+
+    pub async fn send(&self, text: String) -> Result<(), SendError> {
+        self.send_priority(text, Priority::Normal).await
+    }
+
+    pub async fn send_priority(&self, text: String, priority: Priority) -> Result<(), SendError> {
+        self.queue(priority, None, text).await
+    }
+
+    pub async fn send_to_target(&self, target: Target, text: String) -> Result<(), SendError> {
+        self.queue(Priority::Normal, Some(target), text).await
+    }
+
+    async fn queue(
+        &self,
+        priority: Priority,
+        target: Option<Target>,
+        text: String,
+    ) -> Result<(), SendError> {
+        self.tx
+            .send((
+                priority,
+                Outgoing::Message {
+                    target,
+                    text,
+                    spacing: Duration::ZERO,
+                },
+            ))
+            .await
+            .map_err(|_| SendError::Closed)
+    }
+}
+
+///
+/// The messages queued between two channel switches, sent before the switch that ends them.
+///
+#[derive(Debug, Default)]
+struct Segment {
+    pending: [VecDeque<Outgoing>; 3],
+    switch: Option<String>,
+}
+
+///
+/// The outgoing messages of a chat, shared by its connections so nothing queued is lost on a reconnect.
+///
+#[derive(Debug)]
+pub struct OutgoingQueue {
+    rx: Receiver<(Priority, Outgoing)>,
+    // never empty, only the last segment has no switch
+    segments: VecDeque<Segment>,
+}
+
+impl OutgoingQueue {
+    pub fn new(rx: Receiver<(Priority, Outgoing)>) -> Self {
+        Self {
+            rx,
+            segments: VecDeque::from([Segment::default()]),
+        }
+    }
+
+    ///
+    /// The queued message of the highest priority, waiting for one if there is none.
+    /// A channel switch is only returned once the messages queued before it are.
+    ///
+    /// Cancel safe: messages taken off the channel stay pending until they are returned.
+    ///
+    pub async fn next(&mut self) -> Option<Outgoing> {
+        loop {
+            while let Ok((priority, msg)) = self.rx.try_recv() {
+                self.push(priority, msg);
+            }
+            if let Some(msg) = self.pop() {
+                return Some(msg);
+            }
+
+            let (priority, msg) = self.rx.recv().await?;
+            self.push(priority, msg);
+        }
+    }
+
+    fn push(&mut self, priority: Priority, msg: Outgoing) {
+        let last = self.segments.back_mut().unwrap();
+        match msg {
+            Outgoing::SwitchChannel(channel) => {
+                last.switch = Some(channel);
+                self.segments.push_back(Segment::default());
+            }
+            msg => last.pending[priority.index()].push_back(msg),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Outgoing> {
+        let first = self.segments.front_mut().unwrap();
+        if let Some(msg) = first
+            .pending
+            .iter_mut()
+            .find_map(|pending| pending.pop_front())
+        {
+            return Some(msg);
+        }
+        let channel = first.switch.take()?;
+        self.segments.pop_front();
+        Some(Outgoing::SwitchChannel(channel))
+    }
+
+    ///
+    /// Puts back a message `next` returned that couldn't be written, to be sent first
+    /// on the next connection.
+    ///
+    pub fn requeue(&mut self, msg: Outgoing) {
+        match msg {
+            Outgoing::SwitchChannel(channel) => self.segments.push_front(Segment {
+                switch: Some(channel),
+                ..Default::default()
+            }),
+            msg => {
+                self.segments.front_mut().unwrap().pending[Priority::High.index()].push_front(msg)
+            }
+        }
+    }
+}
@@ -0,0 +1,700 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub use super::color::{Color, InvalidColor, NamedColor};
+pub use super::emote::{emote_url, Emote, EmoteFormat, EmoteTheme};
+pub use super::event::*;
+pub use super::message::{
+    ChatMessage, ChatMessageBuilder, ContentFlag, FlagCategory, HypeChat, ReplyInfo, ReplyParent,
+    SharedChatSource,
+};
+pub use super::policy::NoticeAction;
+
+///
+/// Checks the IRC command of a line, skipping its tags and prefix.
+///
+pub fn is_command(str: &str, command: &str) -> bool {
+    let mut parts = str.split(' ');
+    let mut part = parts.next();
+    if part.is_some_and(|p| p.starts_with('@')) {
+        part = parts.next();
+    }
+    if part.is_some_and(|p| p.starts_with(':')) {
+        part = parts.next();
+    }
+    part.is_some_and(|p| p.trim_end() == command)
+}
+
+///
+/// The `msg-id` given to the NOTICEs of a failed login, which Twitch sends without one,
+/// so a `NoticePolicy` can act on them.
+///
+pub const LOGIN_FAILED: &str = "login_failed";
+
+///
+/// Whether a NOTICE message says the token was rejected, eg
+/// "Login authentication failed" or "Improperly formatted auth".
+///
+fn is_login_failure(message: &str) -> bool {
+    let message = message.to_lowercase();
+    ["login authentication failed", "improperly formatted auth"]
+        .iter()
+        .any(|phrase| message.contains(phrase))
+}
+
+///
+/// Whether an ERROR or NOTICE line says the account logged in elsewhere,
+/// going by the wording such messages use, as Twitch has no `msg-id` for it.
+///
+pub fn is_login_conflict(str: &str) -> bool {
+    if !is_command(str, "ERROR") && !is_command(str, "NOTICE") {
+        return false;
+    }
+
+    let message = str.rsplit_once(" :").map_or(str, |(_, message)| message);
+    let message = message.to_lowercase();
+    [
+        "logged in elsewhere",
+        "logged in from another location",
+        "already logged in",
+        "duplicate login",
+        "ghosted",
+    ]
+    .iter()
+    .any(|phrase| message.contains(phrase))
+}
+
+///
+/// The first `#channel` parameter of a line, without the `#`.
+///
+pub fn channel(str: &str) -> Option<&str> {
+    let str = str.split_once("\r\n").map_or(str, |(line, _)| line);
+    let str = match str.strip_prefix('@') {
+        Some(tail) => tail.split_once(' ')?.1,
+        None => str,
+    };
+    let head = str.strip_prefix(':').unwrap_or(str);
+    let head = head.split_once(" :").map_or(head, |(head, _)| head);
+    head.split(' ').find_map(|part| part.strip_prefix('#'))
+}
+
+///
+/// The name Twitch uses for a channel, lowercase and without the `#`.
+///
+pub fn normalize_channel(channel: &str) -> String {
+    channel.trim().trim_start_matches('#').to_lowercase()
+}
+
+///
+/// The nick of a `:nick!user@host` prefix.
+///
+pub fn prefix_nick(str: &str) -> Option<&str> {
+    let str = match str.strip_prefix('@') {
+        Some(tail) => tail.split_once(' ')?.1,
+        None => str,
+    };
+    let prefix = str.strip_prefix(':')?.split(' ').next()?;
+    Some(prefix.split_once('!').map_or(prefix, |(nick, _)| nick))
+}
+
+///
+/// Parses a PRIVMSG line, with or without tags.
+///
+pub fn parse_privmsg(str: &str) -> Option<ChatMessage> {
+    if !is_command(str, "PRIVMSG") {
+        return None;
+    }
+
+    if str.starts_with('@') {
+        format_user_message_with_tags(str)
+    } else {
+        format_user_message(str)
+    }
+}
+
+///
+/// Parses the `@key=value;...` tag block of a line, with the values unescaped.
+///
+pub fn parse_tags(str: &str) -> HashMap<String, String> {
+    let tags = str.split_once(' ').map_or(str, |(tags, _)| tags);
+    tag_map(tags)
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), unescape_tag_value(value)))
+        .collect()
+}
+
+fn format_user_message(str: &str) -> Option<ChatMessage> {
+    let str = str.split_once("\r\n").map_or(str, |(line, _)| line);
+
+    let author = if let Some((author, _)) = str.split_once('!') {
+        Some(author.get(1..).unwrap().to_string())
+    } else {
+        None
+    };
+
+    let message = str.splitn(3, ':').last().unwrap().to_string();
+    let channel = channel(str).map(|channel| channel.to_string());
+
+    if let (Some(author), message) = (author, message) {
+        Some(ChatMessage {
+            login: Some(author.clone()),
+            author,
+            color: None,
+            message,
+            channel,
+            display_channel: None,
+            id: None,
+            reply: None,
+            hype_chat: None,
+            emotes: vec![],
+            is_emote_only: false,
+            flags: vec![],
+            room_id: None,
+            source: None,
+            client_nonce: None,
+        })
+    } else {
+        None
+    }
+}
+
+fn format_user_message_with_tags(str: &str) -> Option<ChatMessage> {
+    let str = str.split_once("\r\n").map_or(str, |(line, _)| line);
+
+    let (tags, author_info, message) = {
+        let (tags, tail) = match str.split_once(" :") {
+            Some((tags, tail)) => (tags, tail),
+            None => return None,
+        };
+
+        let (author_info, message) = match tail.split_once(" :") {
+            Some((author_info, message)) => (author_info, message),
+            None => return None,
+        };
+        (tags, author_info, message)
+    };
+
+    let tags = tag_map(tags);
+
+    let author = match tags.get("display-name").as_mut() {
+        Some(author) => author.to_string(),
+        None => return None,
+    };
+
+    let login = author_info
+        .split_once('!')
+        .map(|(login, _)| login.to_string());
+    let color = tags.get("color").as_mut().map(|color| color.to_string());
+    let id = tags.get("id").map(|id| id.to_string());
+    let reply = parse_reply(&tags);
+    let hype_chat = parse_hype_chat(&tags);
+    let emotes = tags
+        .get("emotes")
+        .map(|v| parse_emotes(v))
+        .unwrap_or_default();
+    let flags = tags
+        .get("flags")
+        .map(|v| parse_flags(v))
+        .unwrap_or_default();
+
+    Some(ChatMessage {
+        author,
+        login,
+        color,
+        message: message.to_owned(),
+        channel: channel(str).map(|channel| channel.to_string()),
+        display_channel: None,
+        id,
+        reply,
+        hype_chat,
+        is_emote_only: tags.get("emote-only") == Some(&"1") || is_emote_only(message, &emotes),
+        emotes,
+        flags,
+        room_id: tags.get("room-id").map(|room_id| room_id.to_string()),
+        source: parse_shared_chat_source(&tags),
+        client_nonce: tags.get("client-nonce").map(|nonce| nonce.to_string()),
+    })
+}
+
+fn parse_reply(tags: &HashMap<&str, &str>) -> Option<ReplyInfo> {
+    let parent = |prefix: &str| {
+        let tag = |key: &str| tags.get(format!("{}-{}", prefix, key).as_str()).copied();
+        Some(ReplyParent {
+            msg_id: tag("msg-id")?.to_string(),
+            user_login: tag("user-login").unwrap_or_default().to_string(),
+            display_name: tag("display-name").unwrap_or_default().to_string(),
+        })
+    };
+
+    Some(ReplyInfo {
+        parent: parent("reply-parent")?,
+        parent_body: tags
+            .get("reply-parent-msg-body")
+            .map(|body| unescape_tag_value(body))
+            .unwrap_or_default(),
+        thread_parent: parent("reply-thread-parent"),
+    })
+}
+
+///
+/// Parses an `emotes` tag value, eg `25:0-4,12-16/1902:6-10`.
+///
+pub fn parse_emotes(value: &str) -> Vec<Emote> {
+    value
+        .split('/')
+        .filter_map(|emote| {
+            let (id, ranges) = emote.split_once(':')?;
+            let ranges = ranges
+                .split(',')
+                .filter_map(|range| {
+                    let (start, end) = range.split_once('-')?;
+                    Some((start.parse().ok()?, end.parse().ok()?))
+                })
+                .collect();
+            Some(Emote {
+                id: id.to_string(),
+                ranges,
+            })
+        })
+        .collect()
+}
+
+///
+/// Whether the `emotes` spans cover every character of `message` but the spaces between them.
+///
+pub fn is_emote_only(message: &str, emotes: &[Emote]) -> bool {
+    if emotes.is_empty() {
+        return false;
+    }
+    message.chars().enumerate().all(|(i, c)| {
+        c.is_whitespace()
+            || emotes
+                .iter()
+                .flat_map(|emote| emote.ranges.iter())
+                .any(|(start, end)| (*start..=*end).contains(&i))
+    })
+}
+
+///
+/// Parses a `flags` tag value, eg `0-4:P.6,6-10:A.7/P.6`.
+/// Ranges without a category, as Twitch sometimes sends, are left out.
+///
+pub fn parse_flags(value: &str) -> Vec<ContentFlag> {
+    value
+        .split(',')
+        .filter_map(|flag| {
+            let (range, categories) = flag.split_once(':')?;
+            let (start, end) = range.split_once('-')?;
+            Some((start.parse().ok()?, end.parse().ok()?, categories))
+        })
+        .flat_map(|(start, end, categories)| {
+            categories.split('/').filter_map(move |category| {
+                let (code, severity) = category.split_once('.')?;
+                Some(ContentFlag {
+                    start,
+                    end,
+                    category: FlagCategory::from_code(code),
+                    severity: severity.parse().ok()?,
+                })
+            })
+        })
+        .collect()
+}
+
+///
+/// The `source-*` tags, left out when the message was sent in the joined room itself.
+///
+fn parse_shared_chat_source(tags: &HashMap<&str, &str>) -> Option<SharedChatSource> {
+    let room_id = tags.get("source-room-id").filter(|id| !id.is_empty())?;
+    if tags.get("room-id") == Some(room_id) {
+        return None;
+    }
+    Some(SharedChatSource {
+        room_id: room_id.to_string(),
+        msg_id: tags
+            .get("source-id")
+            .filter(|id| !id.is_empty())
+            .map(|id| id.to_string()),
+        only: tags.get("source-only") == Some(&"1"),
+    })
+}
+
+fn parse_hype_chat(tags: &HashMap<&str, &str>) -> Option<HypeChat> {
+    Some(HypeChat {
+        amount: tags.get("pinned-chat-paid-amount")?.parse().ok()?,
+        currency: tags.get("pinned-chat-paid-currency")?.to_string(),
+        exponent: tags
+            .get("pinned-chat-paid-exponent")
+            .and_then(|exponent| exponent.parse().ok())
+            .unwrap_or_default(),
+        level: tags
+            .get("pinned-chat-paid-level")
+            .unwrap_or(&"ONE")
+            .to_string(),
+    })
+}
+
+pub fn parse_user_notice(str: &str) -> Option<ChatEvent> {
+    let str = str.split_once("\r\n").map_or(str, |(line, _)| line);
+    let (tags, tail) = str.split_once(' ')?;
+    let tags = tag_map(tags);
+    let message = tail
+        .strip_prefix(':')
+        .and_then(|tail| tail.split_once(" :"))
+        .map(|(_, message)| message.to_string());
+
+    let tag = |key: &str| {
+        tags.get(key)
+            .map(|v| unescape_tag_value(v))
+            .unwrap_or_default()
+    };
+    let number = |key: &str| tags.get(key).and_then(|v| v.parse::<u32>().ok());
+    let sub_plan = tags
+        .get("msg-param-sub-plan")
+        .and_then(|plan| SubPlan::from_tag(plan));
+
+    match *tags.get("msg-id")? {
+        msg_id @ ("sub" | "resub") => Some(ChatEvent::Sub(SubEvent {
+            user: tag("display-name"),
+            user_login: tag("login"),
+            is_resub: msg_id == "resub",
+            sub_plan,
+            sub_plan_name: tag("msg-param-sub-plan-name"),
+            cumulative_months: number("msg-param-cumulative-months").unwrap_or_default(),
+            streak_months: number("msg-param-streak-months").filter(|months| *months > 0),
+            message,
+        })),
+        "subgift" => Some(ChatEvent::SubGift(SubGiftEvent {
+            gifter: tag("display-name"),
+            gifter_login: tag("login"),
+            recipient_display_name: tag("msg-param-recipient-display-name"),
+            recipient_id: tag("msg-param-recipient-id"),
+            sub_plan,
+            sub_plan_name: tag("msg-param-sub-plan-name"),
+            gift_months: number("msg-param-gift-months").unwrap_or_default(),
+            months: number("msg-param-months").unwrap_or_default(),
+        })),
+        "submysterygift" => Some(ChatEvent::SubMysteryGift(SubMysteryGiftEvent {
+            gifter: tag("display-name"),
+            gifter_login: tag("login"),
+            sub_plan,
+            count: number("msg-param-mass-gift-count").unwrap_or_default(),
+        })),
+        msg_id @ ("standardpayforward" | "communitypayforward") => {
+            let optional = |key: &str| Some(tag(key)).filter(|v| !v.is_empty());
+            Some(ChatEvent::PayForward(PayForwardEvent {
+                user: tag("display-name"),
+                user_login: tag("login"),
+                prior_gifter: optional("msg-param-prior-gifter-display-name"),
+                recipient: optional("msg-param-recipient-display-name")
+                    .filter(|_| msg_id == "standardpayforward"),
+            }))
+        }
+        "viewermilestone" => Some(ChatEvent::ViewerMilestone(ViewerMilestoneEvent {
+            user: tag("display-name"),
+            user_login: tag("login"),
+            category: tag("msg-param-category"),
+            value: number("msg-param-value").unwrap_or_default(),
+            message,
+        })),
+        "bitsbadgetier" => Some(ChatEvent::BitsBadgeTier(BitsBadgeTierEvent {
+            user: tag("display-name"),
+            user_login: tag("login"),
+            threshold: number("msg-param-threshold").unwrap_or_default(),
+            message,
+        })),
+        "announcement" => Some(ChatEvent::Announcement(AnnouncementEvent {
+            user: tag("display-name"),
+            user_login: tag("login"),
+            color: Some(tag("msg-param-color")).filter(|v| !v.is_empty()),
+            message: message.unwrap_or_default(),
+        })),
+        "raid" => Some(ChatEvent::Raid(RaidEvent {
+            user: tag("msg-param-displayName"),
+            user_login: tag("msg-param-login"),
+            viewer_count: number("msg-param-viewerCount").unwrap_or_default(),
+        })),
+        msg_id => Some(ChatEvent::UserNotice(UserNotice {
+            msg_id: msg_id.to_string(),
+            user: tag("display-name"),
+            user_login: tag("login"),
+            system_msg: tag("system-msg"),
+            message,
+            params: tags
+                .iter()
+                .filter(|(key, _)| key.starts_with("msg-param-"))
+                .map(|(key, value)| (key.to_string(), unescape_tag_value(value)))
+                .collect(),
+        })),
+    }
+}
+
+pub fn parse_notice(str: &str) -> Option<ChatEvent> {
+    let str = str.split_once("\r\n").map_or(str, |(line, _)| line);
+    let (tags, rest) = match str.split_once(' ') {
+        Some((tags, rest)) if tags.starts_with('@') => (tag_map(tags), rest),
+        _ => (HashMap::new(), str),
+    };
+
+    let (head, message) = rest.strip_prefix(':').unwrap_or(rest).split_once(" :")?;
+    let channel = head
+        .split(' ')
+        .find_map(|part| part.strip_prefix('#'))
+        .map(|channel| channel.to_string());
+    let msg_id = tags
+        .get("msg-id")
+        .map(|id| id.to_string())
+        .or_else(|| is_login_failure(message).then(|| LOGIN_FAILED.to_string()));
+
+    let role = match msg_id.as_deref() {
+        Some("mod_success") => Some((Role::Moderator, true)),
+        Some("unmod_success") => Some((Role::Moderator, false)),
+        Some("vip_success") => Some((Role::Vip, true)),
+        Some("unvip_success") => Some((Role::Vip, false)),
+        _ => None,
+    };
+
+    // "You have added <user> as a moderator of this channel."
+    if let (Some((role, added)), Some(user)) = (role, message.split(' ').nth(3)) {
+        return Some(ChatEvent::RoleChanged(RoleChanged {
+            user: user.to_string(),
+            role,
+            added,
+        }));
+    }
+
+    // the target is the last word, going by the wording like for the roles above
+    let target = message
+        .split_whitespace()
+        .last()
+        .map(|word| word.trim_end_matches(['.', '!']).trim_start_matches('@'))
+        .filter(|target| !target.is_empty());
+    if let (Some("shoutout_success"), Some(target)) = (msg_id.as_deref(), target) {
+        return Some(ChatEvent::Shoutout(Shoutout {
+            channel,
+            target: target.to_string(),
+        }));
+    }
+
+    Some(ChatEvent::Notice(Notice {
+        channel,
+        msg_id,
+        message: message.to_string(),
+    }))
+}
+
+pub fn parse_clear_chat(str: &str) -> Option<ClearChat> {
+    let str = str.split_once("\r\n").map_or(str, |(line, _)| line);
+    let (tags, rest) = match str.split_once(' ') {
+        Some((tags, rest)) if tags.starts_with('@') => (tag_map(tags), rest),
+        _ => (HashMap::new(), str),
+    };
+    let head = rest.strip_prefix(':').unwrap_or(rest);
+
+    Some(ClearChat {
+        channel: channel(str)?.to_string(),
+        target_login: head
+            .split_once(" :")
+            .map(|(_, login)| login.trim().to_string()),
+        target_user_id: tags.get("target-user-id").map(|id| id.to_string()),
+        ban_duration: tags
+            .get("ban-duration")
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs),
+    })
+}
+
+pub fn parse_clear_msg(str: &str) -> Option<ClearMsg> {
+    let str = str.split_once("\r\n").map_or(str, |(line, _)| line);
+    let (tags, rest) = str.split_once(' ')?;
+    let tags = tag_map(tags);
+    let rest = rest.strip_prefix(':').unwrap_or(rest);
+
+    Some(ClearMsg {
+        channel: channel(str)?.to_string(),
+        login: tags.get("login").unwrap_or(&"").to_string(),
+        target_msg_id: tags.get("target-msg-id")?.to_string(),
+        message: rest
+            .split_once(" :")
+            .map(|(_, message)| message.to_string())
+            .unwrap_or_default(),
+    })
+}
+
+pub fn parse_room_state(str: &str) -> Option<RoomState> {
+    let str = str.split_once("\r\n").map_or(str, |(line, _)| line);
+    let (tags, _) = str.split_once(' ')?;
+    let tags = tag_map(tags);
+    let flag = |key: &str| tags.get(key).map(|v| *v == "1");
+
+    Some(RoomState {
+        channel: channel(str)?.trim_end().to_string(),
+        room_id: tags.get("room-id").map(|id| id.to_string()),
+        emote_only: flag("emote-only"),
+        followers_only: tags.get("followers-only").and_then(|v| v.parse().ok()),
+        r9k: flag("r9k"),
+        slow: tags.get("slow").and_then(|v| v.parse().ok()),
+        subs_only: flag("subs-only"),
+        broadcaster_lang: tags
+            .get("broadcaster-lang")
+            .filter(|lang| !lang.is_empty())
+            .map(|lang| lang.to_string()),
+    })
+}
+
+pub fn parse_user_state(str: &str) -> Option<SelfUser> {
+    let str = str.split_once("\r\n").map_or(str, |(line, _)| line);
+    let (tags, _) = str.split_once(' ')?;
+    let tags = tag_map(tags);
+
+    Some(SelfUser {
+        display_name: tags.get("display-name")?.to_string(),
+        color: tags
+            .get("color")
+            .filter(|color| !color.is_empty())
+            .map(|color| color.to_string()),
+        user_id: tags.get("user-id").map(|id| id.to_string()),
+        emote_sets: tags
+            .get("emote-sets")
+            .map(|sets| {
+                sets.split(',')
+                    .filter(|set| !set.is_empty())
+                    .map(|set| set.to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+    })
+}
+
+pub fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+///
+/// Reverses the IRCv3 tag value escaping, eg `\s` for a space.
+///
+pub fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+///
+/// IRCv3 allows 8191 bytes of tags from a server, and a Twitch line has a few dozen tags.
+/// Anything past these limits is dropped, so a bad tag block can't grow the map unbounded.
+///
+const MAX_TAG_BLOCK_LEN: usize = 8191;
+const MAX_TAGS: usize = 128;
+
+fn tag_map(tags: &str) -> HashMap<&str, &str> {
+    let mut tags = tags.strip_prefix('@').unwrap_or(tags);
+    if tags.len() > MAX_TAG_BLOCK_LEN {
+        eprintln!(
+            "Truncating a tag block of {} bytes to {}",
+            tags.len(),
+            MAX_TAG_BLOCK_LEN
+        );
+        let mut end = MAX_TAG_BLOCK_LEN;
+        while !tags.is_char_boundary(end) {
+            end -= 1;
+        }
+        // a tag cut in half is left out entirely
+        tags = tags[..end].rsplit_once(';').map_or("", |(head, _)| head);
+    }
+
+    let mut map = HashMap::new();
+    for (i, pair) in tags.split(';').enumerate() {
+        if i == MAX_TAGS {
+            eprintln!("Skipping tags past the first {}", MAX_TAGS);
+            break;
+        }
+        if let Some((key, value)) = pair.split_once('=') {
+            map.insert(key, value);
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hype_chat_tags_are_parsed() {
+        let line = "@badge-info=;badges=glhf-pledge/1;color=;display-name=HypeUser;emotes=;\
+            first-msg=0;flags=;id=f6fb34f8-562f-4b4d-b628-32113d0ef4b0;mod=0;\
+            pinned-chat-paid-amount=500;pinned-chat-paid-canonical-amount=500;\
+            pinned-chat-paid-currency=USD;pinned-chat-paid-exponent=2;\
+            pinned-chat-paid-is-system-message=0;pinned-chat-paid-level=TWO;returning-chatter=0;\
+            room-id=12345678;subscriber=0;tmi-sent-ts=1687471984306;turbo=0;user-id=87654321;\
+            user-type= :hypeuser!hypeuser@hypeuser.tmi.twitch.tv PRIVMSG #channel :hype!";
+        let message = parse_privmsg(line).unwrap();
+        assert_eq!(message.message, "hype!");
+
+        let hype_chat = message.hype_chat.expect("the paid tags should be parsed");
+        assert_eq!(hype_chat.amount, 500);
+        assert_eq!(hype_chat.currency, "USD");
+        assert_eq!(hype_chat.exponent, 2);
+        assert_eq!(hype_chat.level, "TWO");
+        assert_eq!(hype_chat.value(), 5.0);
+
+        let yen = HypeChat {
+            amount: 300,
+            currency: "JPY".to_string(),
+            exponent: 0,
+            level: "ONE".to_string(),
+        };
+        assert_eq!(yen.value(), 300.0);
+    }
+
+    #[test]
+    fn messages_without_paid_tags_have_no_hype_chat() {
+        let line = "@color=;display-name=Viewer;id=abc;room-id=12345678 \
+            :viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #channel :hello";
+        assert!(parse_privmsg(line).unwrap().hype_chat.is_none());
+    }
+
+    #[test]
+    fn login_failures_get_a_msg_id() {
+        for line in [
+            ":tmi.twitch.tv NOTICE * :Login authentication failed",
+            ":tmi.twitch.tv NOTICE * :Improperly formatted auth",
+        ] {
+            let Some(ChatEvent::Notice(notice)) = parse_notice(line) else {
+                panic!("{} should be a notice", line);
+            };
+            assert_eq!(notice.msg_id.as_deref(), Some(LOGIN_FAILED));
+        }
+
+        let line = "@msg-id=msg_banned :tmi.twitch.tv NOTICE #channel :You are permanently banned.";
+        let Some(ChatEvent::Notice(notice)) = parse_notice(line) else {
+            panic!("{} should be a notice", line);
+        };
+        assert_eq!(notice.msg_id.as_deref(), Some("msg_banned"));
+    }
+}
@@ -0,0 +1,71 @@
+#[cfg(feature = "client")]
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "client")]
+use super::parse;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoticeAction {
+    /// Drop the connection and reconnect.
+    Retry,
+    /// Drop the connection and stop reconnecting.
+    Stop,
+    /// Drop the connection and stop reconnecting, as the token was rejected.
+    /// The app can fetch a new one, eg with `AuthServer`, and join again.
+    Reauth,
+}
+
+///
+/// The `msg-id`s that only rule out the joined channel. A `Stop` on one of them moves on to
+/// the next fallback channel, any other `Stop` ends the chat.
+///
+#[cfg(feature = "client")]
+pub(crate) const CHANNEL_MSG_IDS: [&str; 4] = [
+    "msg_channel_suspended",
+    "msg_channel_blocked",
+    "msg_banned",
+    "msg_room_not_found",
+];
+
+///
+/// Decides per NOTICE `msg-id` what the supervisor does with the connection.
+/// NOTICEs without an entry leave the connection alone.
+/// A failed login has no `msg-id` of its own and goes by `parse::LOGIN_FAILED`.
+///
+#[cfg(feature = "client")]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NoticePolicy {
+    pub actions: HashMap<String, NoticeAction>,
+}
+
+#[cfg(feature = "client")]
+impl Default for NoticePolicy {
+    fn default() -> Self {
+        let actions = [
+            ("msg_channel_suspended", NoticeAction::Stop),
+            ("msg_channel_blocked", NoticeAction::Stop),
+            ("msg_banned", NoticeAction::Stop),
+            ("tos_ban", NoticeAction::Stop),
+            (parse::LOGIN_FAILED, NoticeAction::Reauth),
+        ]
+        .into_iter()
+        .map(|(msg_id, action)| (msg_id.to_string(), action))
+        .collect();
+
+        Self { actions }
+    }
+}
+
+#[cfg(feature = "client")]
+impl NoticePolicy {
+    pub fn action(&self, msg_id: &str) -> Option<NoticeAction> {
+        self.actions.get(msg_id).copied()
+    }
+
+    pub fn set(&mut self, msg_id: &str, action: NoticeAction) -> &mut Self {
+        self.actions.insert(msg_id.to_string(), action);
+        self
+    }
+}
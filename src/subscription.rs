@@ -0,0 +1,144 @@
+use std::fmt;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures_core::Stream;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::ReusableBoxFuture;
+
+use super::chat::{ChatEvent, ChatMessage, ClearChat, RaidEvent, SubEvent};
+
+type Received<S> = (Result<S, RecvError>, broadcast::Receiver<S>);
+
+///
+/// Events of a single type, picked out of every event the chat receives,
+/// or out of the raw lines for `Chat::raw_events`.
+/// Each subscription gets its own copy of the events, independent of `Chat::receive_event`.
+/// Also a `Stream`, so `StreamExt` combinators work on it.
+///
+pub struct Subscription<T, S = ChatEvent> {
+    // the receiver lives in the pending `recv`, so a cancelled `recv` loses nothing
+    next: ReusableBoxFuture<'static, Received<S>>,
+    filter: fn(S) -> Option<T>,
+}
+
+impl<T, S> fmt::Debug for Subscription<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription").finish_non_exhaustive()
+    }
+}
+
+async fn receive<S: Clone>(mut rx: broadcast::Receiver<S>) -> Received<S> {
+    let result = rx.recv().await;
+    (result, rx)
+}
+
+impl<T, S: Clone + Send + 'static> Subscription<T, S> {
+    pub(crate) fn new(rx: broadcast::Receiver<S>, filter: fn(S) -> Option<T>) -> Self {
+        Self {
+            next: ReusableBoxFuture::new(receive(rx)),
+            filter,
+        }
+    }
+
+    ///
+    /// The next matching event, or `None` once the chat is gone.
+    /// Events a slow subscriber fell too far behind on are skipped.
+    ///
+    pub async fn recv(&mut self) -> Option<T> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl<T, S: Clone + Send + 'static> Stream for Subscription<T, S> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        loop {
+            let (result, rx) = ready!(self.next.poll(cx));
+            self.next.set(receive(rx));
+            match result {
+                Ok(event) => {
+                    if let Some(event) = (self.filter)(event) {
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("Subscription fell behind, skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+pub(crate) fn all<S>(event: S) -> Option<S> {
+    Some(event)
+}
+
+pub(crate) fn messages(event: ChatEvent) -> Option<ChatMessage> {
+    match event {
+        ChatEvent::Message(msg) => Some(msg),
+        _ => None,
+    }
+}
+
+pub(crate) fn bans(event: ChatEvent) -> Option<ClearChat> {
+    match event {
+        ChatEvent::ClearChat(clear_chat) if clear_chat.is_ban() => Some(clear_chat),
+        _ => None,
+    }
+}
+
+pub(crate) fn subs(event: ChatEvent) -> Option<SubEvent> {
+    match event {
+        ChatEvent::Sub(sub) => Some(sub),
+        _ => None,
+    }
+}
+
+pub(crate) fn raids(event: ChatEvent) -> Option<RaidEvent> {
+    match event {
+        ChatEvent::Raid(raid) => Some(raid),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    fn privmsg_text(line: String) -> Option<String> {
+        line.strip_prefix("PRIVMSG #channel :")
+            .map(|text| text.to_string())
+    }
+
+    #[tokio::test]
+    async fn a_subscription_is_a_stream_of_the_matching_events() {
+        let (tx, rx) = broadcast::channel(16);
+        let subscription = Subscription::new(rx, privmsg_text);
+        for line in ["PRIVMSG #channel :one", "PING", "PRIVMSG #channel :three"] {
+            tx.send(line.to_string()).unwrap();
+        }
+        drop(tx);
+
+        let texts: Vec<String> = subscription.map(|text| text.to_uppercase()).collect().await;
+        assert_eq!(texts, ["ONE", "THREE"]);
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_recv_loses_nothing() {
+        let (tx, rx) = broadcast::channel(16);
+        let mut subscription = Subscription::new(rx, privmsg_text);
+        let cancelled = tokio::time::timeout(Duration::from_millis(10), subscription.recv()).await;
+        assert!(cancelled.is_err());
+
+        tx.send("PRIVMSG #channel :after".to_string()).unwrap();
+        assert_eq!(subscription.recv().await.as_deref(), Some("after"));
+    }
+}
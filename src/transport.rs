@@ -0,0 +1,212 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
+use fast_websocket_client as ws;
+
+pub type TransportError = Box<dyn std::error::Error + Send + Sync>;
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+///
+/// A connection to the chat server, sending and receiving raw IRC text.
+///
+pub trait Transport: Send {
+    fn send_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, Result<(), TransportError>>;
+
+    ///
+    /// Receives the next frame, which may hold several `\r\n` separated lines.
+    ///
+    fn receive(&mut self) -> BoxFuture<'_, Result<String, TransportError>>;
+}
+
+///
+/// Opens a `Transport` for every (re)connect of the supervisor.
+///
+pub trait Connector: Send + Sync + fmt::Debug {
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Transport>, TransportError>>;
+}
+
+///
+/// The default connector, a websocket to the endpoint.
+///
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WebSocketConnector;
+
+impl Connector for WebSocketConnector {
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn Transport>, TransportError>> {
+        Box::pin(async move {
+            let mut conn = ws::connect(endpoint).await?;
+            conn.set_auto_pong(true);
+            Ok(Box::new(WebSocketTransport(conn)) as Box<dyn Transport>)
+        })
+    }
+}
+
+struct WebSocketTransport(ws::client::Online);
+
+impl Transport for WebSocketTransport {
+    fn send_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, Result<(), TransportError>> {
+        Box::pin(self.0.send_string(line))
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Result<String, TransportError>> {
+        Box::pin(async move {
+            let f = self.0.receive_frame().await?;
+            let msg = if let Ok(s) = std::str::from_utf8(&f.payload) {
+                s.to_string()
+            } else {
+                f.payload
+                    .iter()
+                    .map(|v| -> char { (*v).into() })
+                    .collect::<String>()
+            };
+            Ok(msg)
+        })
+    }
+}
+
+///
+/// Feeds every line sent through `inner` to `tap`, with the token of a PASS masked,
+/// for `Chat::sent_lines`.
+///
+pub(crate) struct TappedTransport {
+    pub inner: Box<dyn Transport>,
+    pub tap: tokio::sync::broadcast::Sender<String>,
+}
+
+impl Transport for TappedTransport {
+    fn send_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, Result<(), TransportError>> {
+        Box::pin(async move {
+            self.inner.send_line(line).await?;
+            let line = match line.strip_prefix("PASS ") {
+                Some(_) => "PASS ***".to_string(),
+                None => line.to_string(),
+            };
+            // no subscribers is fine, lines are only kept for the ones there are
+            let _ = self.tap.send(line);
+            Ok(())
+        })
+    }
+
+    fn receive(&mut self) -> BoxFuture<'_, Result<String, TransportError>> {
+        self.inner.receive()
+    }
+}
+
+///
+/// An in-memory stand-in for the chat server, for driving the whole pipeline in tests.
+///
+#[cfg(any(test, feature = "test-util"))]
+pub mod memory {
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    use super::{BoxFuture, Connector, Transport, TransportError};
+
+    ///
+    /// Creates a connector for `Chat::with_connector` and the server side it connects to.
+    ///
+    pub fn pair() -> (MemoryConnector, MemoryServer) {
+        let (accept_tx, accept_rx) = unbounded_channel();
+        (MemoryConnector { accept_tx }, MemoryServer { accept_rx })
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct MemoryConnector {
+        accept_tx: UnboundedSender<MemoryConnection>,
+    }
+
+    #[derive(Debug)]
+    pub struct MemoryServer {
+        accept_rx: UnboundedReceiver<MemoryConnection>,
+    }
+
+    impl MemoryServer {
+        ///
+        /// Waits for the next connection of the client, eg after a reconnect.
+        ///
+        pub async fn accept(&mut self) -> Option<MemoryConnection> {
+            self.accept_rx.recv().await
+        }
+    }
+
+    ///
+    /// The server end of one connection. Dropping it disconnects the client.
+    ///
+    #[derive(Debug)]
+    pub struct MemoryConnection {
+        pub endpoint: String,
+        to_client: UnboundedSender<String>,
+        from_client: UnboundedReceiver<String>,
+    }
+
+    impl MemoryConnection {
+        ///
+        /// Delivers a canned frame to the client.
+        ///
+        pub fn send_frame(&self, frame: &str) -> bool {
+            self.to_client.send(frame.to_string()).is_ok()
+        }
+
+        ///
+        /// The next line the client wrote, `None` once the client dropped the connection.
+        ///
+        pub async fn recv_line(&mut self) -> Option<String> {
+            self.from_client.recv().await
+        }
+    }
+
+    struct MemoryTransport {
+        from_server: UnboundedReceiver<String>,
+        to_server: UnboundedSender<String>,
+    }
+
+    impl Connector for MemoryConnector {
+        fn connect<'a>(
+            &'a self,
+            endpoint: &'a str,
+        ) -> BoxFuture<'a, Result<Box<dyn Transport>, TransportError>> {
+            Box::pin(async move {
+                let (to_client, from_server) = unbounded_channel();
+                let (to_server, from_client) = unbounded_channel();
+
+                self.accept_tx
+                    .send(MemoryConnection {
+                        endpoint: endpoint.to_string(),
+                        to_client,
+                        from_client,
+                    })
+                    .map_err(|_| "memory server dropped")?;
+
+                Ok(Box::new(MemoryTransport {
+                    from_server,
+                    to_server,
+                }) as Box<dyn Transport>)
+            })
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        fn send_line<'a>(&'a mut self, line: &'a str) -> BoxFuture<'a, Result<(), TransportError>> {
+            Box::pin(async move {
+                self.to_server
+                    .send(line.to_string())
+                    .map_err(|_| "connection closed".into())
+            })
+        }
+
+        fn receive(&mut self) -> BoxFuture<'_, Result<String, TransportError>> {
+            Box::pin(async move {
+                self.from_server
+                    .recv()
+                    .await
+                    .ok_or_else(|| "connection closed".into())
+            })
+        }
+    }
+}